@@ -0,0 +1,72 @@
+//! Compares `BiasedArc` against `simple_arc::Arc`, with all clones and drops
+//! on the owning thread, and with them spread over several threads.
+//!
+//! Run with `cargo bench --bench biased_arc`.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc::biased_arc::BiasedArc;
+use arc::simple_arc::Arc;
+
+const ITERATIONS: usize = 10_000_000;
+const THREADS: usize = 4;
+
+fn time(name: &str, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<32} {elapsed:>12.2?} ({:.2} ns/op)",
+        per_op(elapsed, ITERATIONS)
+    );
+}
+
+fn per_op(elapsed: Duration, ops: usize) -> f64 {
+    elapsed.as_nanos() as f64 / ops as f64
+}
+
+fn main() {
+    time("owner only: simple_arc::Arc", || {
+        let a = Arc::new(0u64);
+        for _ in 0..ITERATIONS {
+            drop(black_box(a.clone()));
+        }
+    });
+    time("owner only: BiasedArc", || {
+        let a = BiasedArc::new(0u64);
+        for _ in 0..ITERATIONS {
+            drop(black_box(a.clone()));
+        }
+    });
+
+    // Each thread does ITERATIONS / THREADS clones, so the per op figures
+    // are comparable with the above.
+    time("shared: simple_arc::Arc", || {
+        let a = Arc::new(0u64);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                let a = a.clone();
+                s.spawn(move || {
+                    for _ in 0..ITERATIONS / THREADS {
+                        drop(black_box(a.clone()));
+                    }
+                });
+            }
+        });
+    });
+    time("shared: BiasedArc", || {
+        let a = BiasedArc::new(0u64);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                let a = BiasedArc::share(&a);
+                s.spawn(move || {
+                    for _ in 0..ITERATIONS / THREADS {
+                        drop(black_box(a.clone()));
+                    }
+                });
+            }
+        });
+    });
+}
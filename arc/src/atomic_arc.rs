@@ -0,0 +1,219 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::simple_arc::Arc;
+
+/// A slot holding an [`Arc`] which can be replaced atomically, in the style of
+/// the `arc-swap` crate.
+///
+/// Readers [`load`] a clone of the current [`Arc`] without taking a lock,
+/// while a writer can [`store`], [`swap`] or [`compare_and_swap`] in a new one.
+///
+/// The difficulty is in the reader: it must first load the pointer and then
+/// increment the strong count behind it, and a writer could swap out and drop
+/// the last [`Arc`] in between those two steps. The reader would then be
+/// incrementing a freed counter. To prevent this, readers announce themselves
+/// in a counter for the duration of those two steps, and a writer waits for
+/// any readers which could have seen the old pointer before giving it up.
+///
+/// With a single counter a steady stream of readers could hold off a writer
+/// forever, so there are two, alternating by generation. New readers join the
+/// current generation, and a writer moves everyone on to the next generation
+/// before waiting for the previous one to drain.
+///
+/// [`load`]: AtomicArc::load
+/// [`store`]: AtomicArc::store
+/// [`swap`]: AtomicArc::swap
+/// [`compare_and_swap`]: AtomicArc::compare_and_swap
+pub struct AtomicArc<T> {
+    /// Pointer from [`Arc::into_raw`], the slot owns one strong reference.
+    ptr: AtomicPtr<T>,
+    /// Number of readers in between loading `ptr` and incrementing its strong
+    /// count, for each of the two generations.
+    readers: [AtomicUsize; 2],
+    /// Current generation, only its lowest bit is used to index `readers`.
+    generation: AtomicUsize,
+    /// Writers are serialised with each other (but never with readers), so
+    /// that only one is moving the generation on at a time.
+    writing: AtomicBool,
+    /// `load` hands clones of the current [`Arc`] to any thread, so the slot
+    /// can't be [`Send`] or [`Sync`] unless `Arc<T>` is.
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> AtomicArc<T> {
+    pub fn new(arc: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Arc::into_raw(arc) as *mut T),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            generation: AtomicUsize::new(0),
+            writing: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get a clone of the [`Arc`] currently in the slot.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn load(&self) -> Arc<T> {
+        // SeqCst is used throughout the reader and writer handshake. The
+        // reader stores to `readers` and then loads `ptr`, while the writer
+        // stores to `ptr` and then loads `readers`. Only SeqCst guarantees that
+        // at least one of them sees the other's store. The fences on both
+        // sides are what make that hold: SeqCst on the accesses alone says
+        // nothing about the writer's final load of `readers`.
+        let generation = loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            let readers = &self.readers[generation % 2];
+            readers.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            // A writer may have moved the generation on between our load and
+            // increment, and already finished waiting on the counter we just
+            // joined. Retry in the new generation when that happens.
+            if self.generation.load(Ordering::SeqCst) == generation {
+                break generation;
+            }
+            readers.fetch_sub(1, Ordering::Release);
+        };
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        // Safety: A writer won't drop the Arc it swapped out while we are
+        // registered as a reader, so the allocation is still alive.
+        unsafe { Arc::increment_strong_count(ptr) };
+        // Release to make sure the increment above happens before the writer
+        // gives up its reference.
+        self.readers[generation % 2].fetch_sub(1, Ordering::Release);
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Replace the [`Arc`] in the slot, dropping the previous one.
+    pub fn store(&self, arc: Arc<T>) {
+        drop(self.swap(arc));
+    }
+
+    /// Replace the [`Arc`] in the slot, returning the previous one.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn swap(&self, arc: Arc<T>) -> Arc<T> {
+        self.lock_writer();
+        let old = self
+            .ptr
+            .swap(Arc::into_raw(arc) as *mut T, Ordering::SeqCst);
+        self.wait_for_readers();
+        self.writing.store(false, Ordering::Release);
+        // Safety: The slot owned this strong reference, and no reader can
+        // still be relying on it.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    /// Replace the [`Arc`] in the slot with `new` only if it is still
+    /// `current`, as compared by [`Arc::ptr_eq`].
+    ///
+    /// Returns the previous [`Arc`] on success. On failure `new` is handed
+    /// back to the caller.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Result<Arc<T>, Arc<T>> {
+        self.lock_writer();
+        // Only writers change the pointer, and we are the only writer.
+        let old = self.ptr.load(Ordering::Relaxed);
+        if !std::ptr::eq(old, Arc::as_ptr(current)) {
+            self.writing.store(false, Ordering::Release);
+            return Err(new);
+        }
+        self.ptr
+            .store(Arc::into_raw(new) as *mut T, Ordering::SeqCst);
+        self.wait_for_readers();
+        self.writing.store(false, Ordering::Release);
+        Ok(unsafe { Arc::from_raw(old) })
+    }
+
+    fn lock_writer(&self) {
+        while self.writing.swap(true, Ordering::Acquire) {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Wait until no reader can still be about to increment the strong count
+    /// of an [`Arc`] that has just been swapped out.
+    fn wait_for_readers(&self) {
+        // Readers joining from now on will see the new pointer, so it is only
+        // the previous generation that we need to wait for.
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst);
+        // Pairs with the fence in `load`: either a reader sees the new
+        // generation and pointer, or we see its increment below.
+        fence(Ordering::SeqCst);
+        // Acquire matches the Release decrement in `load`.
+        while self.readers[generation % 2].load(Ordering::Acquire) != 0 {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // Safety: There can't be any readers left as we have exclusive
+        // access, so give up the reference owned by the slot.
+        unsafe { drop(Arc::from_raw(*self.ptr.get_mut())) }
+    }
+}
+
+impl<T> From<Arc<T>> for AtomicArc<T> {
+    fn from(arc: Arc<T>) -> Self {
+        Self::new(arc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn every_version_dropped_once() {
+        const VERSIONS: usize = if cfg!(miri) { 20 } else { 2000 };
+        static NUM_DROPS: [AtomicUsize; VERSIONS] = [const { AtomicUsize::new(0) }; VERSIONS];
+
+        struct DetectDrop(usize);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS[self.0].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let slot = AtomicArc::new(Arc::new(DetectDrop(0)));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    // Versions only ever move forward, and whatever we are
+                    // holding on to can't have been dropped.
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let arc = slot.load();
+                        assert!(arc.0 >= last);
+                        assert_eq!(NUM_DROPS[arc.0].load(Ordering::Relaxed), 0);
+                        last = arc.0;
+                    }
+                });
+            }
+            s.spawn(|| {
+                for version in 1..VERSIONS {
+                    let new = Arc::new(DetectDrop(version));
+                    if version % 2 == 0 {
+                        slot.store(new);
+                    } else {
+                        let current = slot.load();
+                        assert!(slot.compare_and_swap(&current, new).is_ok());
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+
+        // All but the version still in the slot have been dropped.
+        for drops in &NUM_DROPS[..VERSIONS - 1] {
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        }
+        assert_eq!(NUM_DROPS[VERSIONS - 1].load(Ordering::Relaxed), 0);
+        drop(slot);
+        assert_eq!(NUM_DROPS[VERSIONS - 1].load(Ordering::Relaxed), 1);
+    }
+}
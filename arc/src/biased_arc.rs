@@ -0,0 +1,242 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// Added to or subtracted from `shared` for every [`RemoteArc`].
+const ONE: usize = 2;
+/// Set in `shared` once the biased count has reached 0.
+const MERGED: usize = 1;
+
+struct BiasedData<T> {
+    /// Number of [`BiasedArc`]s. These can't leave the owning thread, so a
+    /// plain counter is enough.
+    biased: Cell<usize>,
+    /// Number of [`RemoteArc`]s (in steps of [`ONE`]), plus the [`MERGED`]
+    /// flag in the lowest bit.
+    shared: AtomicUsize,
+    data: T,
+}
+
+/// A reference counted pointer biased towards the thread that created it.
+///
+/// Most clones and drops of a shared value tend to happen on the thread that
+/// created it. Those pay for an atomic read-modify-write with `Arc` even when
+/// no other thread is involved. Biased reference counting splits the count
+/// in two: a plain counter for the owning thread, and an atomic one for
+/// everyone else.
+///
+/// Here the split is made by type. A [`BiasedArc`] is neither [`Send`] nor
+/// [`Sync`], so it stays on the owning thread and can use the plain counter.
+/// To hand the data to another thread, [`BiasedArc::share`] creates a
+/// [`RemoteArc`], which uses the atomic counter.
+///
+/// The two counts are merged when the last [`BiasedArc`] is dropped: the
+/// owner marks the atomic counter as merged, and from then on whoever brings
+/// the atomic count to 0 frees the allocation. If there are no
+/// [`RemoteArc`]s at that point, the owner does it straight away.
+pub struct BiasedArc<T> {
+    ptr: NonNull<BiasedData<T>>,
+    // NonNull already makes this !Send and !Sync, which is what keeps the
+    // biased counter on one thread. Spelled out as it is load bearing.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// A handle to the data of a [`BiasedArc`] which can be used from any thread.
+pub struct RemoteArc<T> {
+    ptr: NonNull<BiasedData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for RemoteArc<T> {}
+unsafe impl<T: Send + Sync> Sync for RemoteArc<T> {}
+
+impl<T> BiasedArc<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(BiasedData {
+                biased: Cell::new(1),
+                shared: AtomicUsize::new(0),
+                data,
+            }))),
+            _not_send: PhantomData,
+        }
+    }
+
+    fn data(&self) -> &BiasedData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Create a [`RemoteArc`] to the same data, to be sent to another thread.
+    pub fn share(biased: &Self) -> RemoteArc<T> {
+        // We hold a biased reference, so the counts can't have been merged.
+        if biased.data().shared.fetch_add(ONE, Ordering::Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        RemoteArc { ptr: biased.ptr }
+    }
+
+    /// Number of [`BiasedArc`]s and [`RemoteArc`]s pointing to the data.
+    pub fn strong_count(biased: &Self) -> usize {
+        biased.data().biased.get() + biased.data().shared.load(Ordering::Relaxed) / ONE
+    }
+}
+
+impl<T> Deref for BiasedArc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.data().data
+    }
+}
+
+// The fast path, no atomics involved.
+impl<T> Clone for BiasedArc<T> {
+    fn clone(&self) -> Self {
+        let biased = &self.data().biased;
+        biased.set(biased.get().checked_add(1).expect("biased count overflow"));
+        Self {
+            ptr: self.ptr,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for BiasedArc<T> {
+    fn drop(&mut self) {
+        let biased = &self.data().biased;
+        biased.set(biased.get() - 1);
+        if biased.get() != 0 {
+            return;
+        }
+        // Merge: from here on the atomic count alone decides when to free.
+        // Release so that our use of the data happens before a RemoteArc
+        // frees it, Acquire so that theirs happens before we might.
+        let shared = self.data().shared.fetch_or(MERGED, Ordering::AcqRel);
+        if shared / ONE == 0 {
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) }
+        }
+    }
+}
+
+impl<T> RemoteArc<T> {
+    fn data(&self) -> &BiasedData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Deref for RemoteArc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.data().data
+    }
+}
+
+impl<T> Clone for RemoteArc<T> {
+    fn clone(&self) -> Self {
+        if self.data().shared.fetch_add(ONE, Ordering::Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        Self { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for RemoteArc<T> {
+    fn drop(&mut self) {
+        // Only free when we are the last RemoteArc and the owner has already
+        // merged. Before the merge, the owner is the one to free.
+        if self.data().shared.fetch_sub(ONE, Ordering::Release) == ONE | MERGED {
+            fence(Ordering::Acquire);
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn owner_drops_first() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let owner = BiasedArc::new(DetectDrop);
+        let owner2 = owner.clone();
+        let dropped = Barrier::new(3);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                let remote = BiasedArc::share(&owner);
+                let dropped = &dropped;
+                s.spawn(move || {
+                    dropped.wait();
+                    // Still usable after the merge.
+                    let _: &DetectDrop = &remote.clone();
+                    drop(remote);
+                });
+            }
+            drop((owner, owner2));
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+            dropped.wait();
+        });
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn remotes_drop_first() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let owner = BiasedArc::new(DetectDrop);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                let remote = BiasedArc::share(&owner);
+                s.spawn(move || drop(remote.clone()));
+            }
+        });
+        assert_eq!(BiasedArc::strong_count(&owner), 1);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(owner);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn merge_race() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let rounds = if cfg!(miri) { 20 } else { 1000 };
+        for round in 1..=rounds {
+            let owner = BiasedArc::new(DetectDrop);
+            let remote = BiasedArc::share(&owner);
+            thread::scope(|s| {
+                s.spawn(move || drop(remote));
+                drop(owner);
+            });
+            // Whichever side went last freed the data, and only once.
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), round);
+        }
+    }
+}
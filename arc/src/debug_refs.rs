@@ -0,0 +1,182 @@
+//! Tracking of who holds the `simple_arc::Arc`s to each allocation, to hunt
+//! down leaks.
+//!
+//! With the `debug-refs` feature every `Arc` records the location it was
+//! created at (by `new`, `clone`, `upgrade`, ...) in a global registry, keyed
+//! by allocation. [`live_allocations`] then lists every allocation that is
+//! still alive, along with where its references came from.
+//!
+//! Without the feature the [`Holder`] stored in each `Arc` is zero-sized and
+//! all of its methods are empty, so nothing is left of this at runtime.
+
+use std::ptr::NonNull;
+
+#[cfg(feature = "debug-refs")]
+pub use registry::{live_allocations, print_live_allocations, HeldBy, LiveAllocation};
+
+#[cfg(all(test, feature = "debug-refs"))]
+pub(crate) use registry::held_from;
+
+#[cfg(feature = "debug-refs")]
+mod registry {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::panic::Location;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// What a reference to an allocation is held by.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum HeldBy {
+        /// An `Arc` created at this location.
+        Location(&'static Location<'static>),
+        /// A pointer from `Arc::into_raw` (or `increment_strong_count`) that
+        /// has not been turned back into an `Arc`.
+        Raw,
+    }
+
+    /// An allocation that is still referenced by at least one `Arc`.
+    #[derive(Debug)]
+    pub struct LiveAllocation {
+        /// Address of the allocation, not of the data within it.
+        pub address: usize,
+        /// Where the references come from, and how many there are of each.
+        pub holders: Vec<(HeldBy, usize)>,
+    }
+
+    impl fmt::Display for LiveAllocation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "allocation {:#x}:", self.address)?;
+            for (held_by, count) in &self.holders {
+                match held_by {
+                    HeldBy::Location(location) => writeln!(f, "  {count} x {location}")?,
+                    HeldBy::Raw => writeln!(f, "  {count} x raw pointer")?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    static REGISTRY: Mutex<BTreeMap<usize, BTreeMap<HeldBy, usize>>> = Mutex::new(BTreeMap::new());
+
+    fn registry() -> MutexGuard<'static, BTreeMap<usize, BTreeMap<HeldBy, usize>>> {
+        // The registry is never left half updated, so a panic elsewhere while
+        // holding the lock doesn't matter.
+        REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(super) fn register(address: usize, held_by: HeldBy) {
+        *registry()
+            .entry(address)
+            .or_default()
+            .entry(held_by)
+            .or_default() += 1;
+    }
+
+    pub(super) fn unregister(address: usize, held_by: HeldBy) {
+        let mut registry = registry();
+        let Some(holders) = registry.get_mut(&address) else {
+            // An allocation that was never registered, such as one handed
+            // over from an Rc.
+            return;
+        };
+        if let Some(count) = holders.get_mut(&held_by) {
+            *count -= 1;
+            if *count == 0 {
+                holders.remove(&held_by);
+            }
+        }
+        if holders.is_empty() {
+            registry.remove(&address);
+        }
+    }
+
+    /// Every allocation with at least one `Arc` (or raw pointer) to it.
+    pub fn live_allocations() -> Vec<LiveAllocation> {
+        registry()
+            .iter()
+            .map(|(&address, holders)| LiveAllocation {
+                address,
+                holders: holders.iter().map(|(&h, &c)| (h, c)).collect(),
+            })
+            .collect()
+    }
+
+    /// Whether any live allocation is held by an `Arc` created at
+    /// `file:line`, for tests checking that the caller is tracked.
+    #[cfg(test)]
+    pub(crate) fn held_from(file: &str, line: u32) -> bool {
+        registry().values().any(|holders| {
+            holders.keys().any(|held_by| {
+                matches!(held_by, HeldBy::Location(location)
+                    if location.file() == file && location.line() == line)
+            })
+        })
+    }
+
+    /// Print [`live_allocations`] to stderr.
+    pub fn print_live_allocations() {
+        for allocation in live_allocations() {
+            eprint!("{allocation}");
+        }
+    }
+}
+
+/// The record of the reference held by a single `Arc`, which is removed from
+/// the registry again when this is dropped.
+#[cfg(feature = "debug-refs")]
+pub(crate) struct Holder {
+    address: usize,
+    held_by: registry::HeldBy,
+}
+
+#[cfg(feature = "debug-refs")]
+impl Holder {
+    #[track_caller]
+    pub(crate) fn new<T: ?Sized>(ptr: NonNull<T>) -> Self {
+        let holder = Self {
+            address: ptr.as_ptr() as *const () as usize,
+            held_by: registry::HeldBy::Location(std::panic::Location::caller()),
+        };
+        registry::register(holder.address, holder.held_by);
+        holder
+    }
+
+    /// Take over a reference that was given up with [`Holder::into_raw`].
+    #[track_caller]
+    pub(crate) fn from_raw<T: ?Sized>(ptr: NonNull<T>) -> Self {
+        let holder = Self::new(ptr);
+        registry::unregister(holder.address, registry::HeldBy::Raw);
+        holder
+    }
+
+    /// Keep the reference registered, but as held by a raw pointer.
+    pub(crate) fn into_raw(self) {
+        registry::register(self.address, registry::HeldBy::Raw);
+    }
+}
+
+#[cfg(feature = "debug-refs")]
+impl Drop for Holder {
+    fn drop(&mut self) {
+        registry::unregister(self.address, self.held_by);
+    }
+}
+
+#[cfg(not(feature = "debug-refs"))]
+pub(crate) struct Holder;
+
+#[cfg(not(feature = "debug-refs"))]
+impl Holder {
+    #[inline(always)]
+    pub(crate) fn new<T: ?Sized>(_ptr: NonNull<T>) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub(crate) fn from_raw<T: ?Sized>(_ptr: NonNull<T>) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub(crate) fn into_raw(self) {}
+}
@@ -0,0 +1,471 @@
+//! Hazard pointers: deferred freeing for lock-free structures, without any
+//! reference counting on the read side.
+//!
+//! A reader announces the pointer it is about to dereference by storing it in
+//! a hazard pointer slot of its own. A writer that has unlinked a node doesn't
+//! free it straight away, but [`retire`]s it. Retired nodes are only freed by
+//! [`reclaim`] once no slot holds their address any more.
+//!
+//! Compared to an `Arc` per node, a read is then a store to a slot which no
+//! other reader writes to, rather than an increment of a counter shared by
+//! every reader of that node.
+//!
+//! A Treiber stack, the classic example:
+//!
+//! ```
+//! use arc::hazard::Domain;
+//! use std::mem::ManuallyDrop;
+//! use std::ptr;
+//! use std::sync::atomic::{AtomicPtr, Ordering};
+//!
+//! struct Node<T> {
+//!     // The data is moved out by `pop`, the node itself is retired later.
+//!     data: ManuallyDrop<T>,
+//!     next: *mut Node<T>,
+//! }
+//!
+//! struct Stack<T> {
+//!     head: AtomicPtr<Node<T>>,
+//! }
+//!
+//! impl<T: Send> Stack<T> {
+//!     fn push(&self, data: T) {
+//!         let node = Box::into_raw(Box::new(Node {
+//!             data: ManuallyDrop::new(data),
+//!             next: ptr::null_mut(),
+//!         }));
+//!         let mut head = self.head.load(Ordering::Relaxed);
+//!         loop {
+//!             unsafe { (*node).next = head };
+//!             match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+//!                 Ok(_) => return,
+//!                 Err(h) => head = h,
+//!             }
+//!         }
+//!     }
+//!
+//!     fn pop(&self) -> Option<T> {
+//!         let mut hazard = Domain::global().hazard_pointer();
+//!         loop {
+//!             let head = hazard.protect(&self.head);
+//!             if head.is_null() {
+//!                 return None;
+//!             }
+//!             // Safe to dereference, head can't be freed while protected.
+//!             let next = unsafe { (*head).next };
+//!             if self
+//!                 .head
+//!                 .compare_exchange(head, next, Ordering::Relaxed, Ordering::Relaxed)
+//!                 .is_ok()
+//!             {
+//!                 let data = unsafe { ptr::read(&*(*head).data) };
+//!                 hazard.reset();
+//!                 unsafe { Domain::global().retire(head) };
+//!                 return Some(data);
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let stack = Stack { head: AtomicPtr::new(ptr::null_mut()) };
+//! std::thread::scope(|s| {
+//!     for t in 0..4 {
+//!         let stack = &stack;
+//!         s.spawn(move || {
+//!             for i in 0..1000 {
+//!                 stack.push(t * 1000 + i);
+//!                 stack.pop().unwrap();
+//!             }
+//!         });
+//!     }
+//! });
+//! assert!(stack.pop().is_none());
+//! ```
+//!
+//! [`retire`]: Domain::retire
+//! [`reclaim`]: Domain::reclaim
+
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// Minimum number of retired nodes before [`Domain::retire`] tries to
+/// reclaim them.
+const RECLAIM_THRESHOLD: usize = 64;
+
+/// A hazard pointer slot. Once allocated, slots are only freed along with
+/// their [`Domain`], and are reused by later [`HazardPointer`]s.
+struct Slot {
+    /// The pointer currently protected through this slot, or null.
+    hazard: AtomicPtr<()>,
+    /// Whether a [`HazardPointer`] currently owns this slot.
+    active: AtomicBool,
+    /// Next slot in the list, which never changes once the slot is added.
+    next: *mut Slot,
+}
+
+/// A node which has been retired, but not yet freed.
+struct Retired {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+    next: *mut Retired,
+}
+
+/// A set of hazard pointer slots and the retired nodes they protect.
+///
+/// Nodes retired in a domain are only checked against the hazard pointers of
+/// that same domain. Most users can share [`Domain::global`], a separate
+/// domain keeps the reclamation of one structure from scanning the hazard
+/// pointers of every other.
+pub struct Domain {
+    /// List of slots, which only grows.
+    slots: AtomicPtr<Slot>,
+    slot_count: AtomicUsize,
+    /// Stack of retired nodes.
+    retired: AtomicPtr<Retired>,
+    retired_count: AtomicUsize,
+}
+
+// The raw pointers inside are only touched through atomics, and `retire`
+// requires the nodes to be fine to send across.
+unsafe impl Send for Domain {}
+unsafe impl Sync for Domain {}
+
+/// Ownership of a hazard pointer slot, used to protect one pointer at a time.
+///
+/// Acquiring one involves a search through the slots of the domain, so a
+/// thread should hold on to it across operations where it can.
+pub struct HazardPointer<'domain> {
+    slot: &'domain Slot,
+    // Protection is a promise made by one thread, keep it there.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Domain {
+    pub const fn new() -> Self {
+        Self {
+            slots: AtomicPtr::new(ptr::null_mut()),
+            slot_count: AtomicUsize::new(0),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            retired_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// The domain shared by everything that doesn't bring its own.
+    pub fn global() -> &'static Self {
+        static GLOBAL: Domain = Domain::new();
+        &GLOBAL
+    }
+
+    /// Acquire a hazard pointer slot, reusing an inactive one if possible.
+    pub fn hazard_pointer(&self) -> HazardPointer<'_> {
+        let mut slot = self.slots.load(Ordering::Acquire);
+        while !slot.is_null() {
+            // Safety: Slots are never freed while the domain is alive.
+            let s = unsafe { &*slot };
+            if !s.active.load(Ordering::Relaxed)
+                && s.active
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return HazardPointer {
+                    slot: s,
+                    _not_send: PhantomData,
+                };
+            }
+            slot = s.next;
+        }
+        // All slots are taken, add a new one at the front.
+        let new = Box::into_raw(Box::new(Slot {
+            hazard: AtomicPtr::new(ptr::null_mut()),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.slots.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*new).next = head };
+            // Release so that a thread following the list sees `next`.
+            match self
+                .slots
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(h) => head = h,
+            }
+        }
+        self.slot_count.fetch_add(1, Ordering::Relaxed);
+        HazardPointer {
+            slot: unsafe { &*new },
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Hand over a node that has been unlinked from its structure, to be
+    /// dropped once no hazard pointer protects it.
+    ///
+    /// This might reclaim retired nodes, including ones retired by other
+    /// threads.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from [`Box::into_raw`], must no longer be reachable
+    /// for threads that haven't protected it yet, and may not be retired
+    /// twice. The node may be dropped on any thread, so it must be fine to
+    /// send it across (such as a node holding a `Send` value).
+    pub unsafe fn retire<T>(&self, ptr: *mut T) {
+        unsafe fn drop_box<T>(ptr: *mut ()) {
+            drop(Box::from_raw(ptr as *mut T));
+        }
+        let retired = Box::into_raw(Box::new(Retired {
+            ptr: ptr as *mut (),
+            drop: drop_box::<T>,
+            next: ptr::null_mut(),
+        }));
+        self.push_retired(retired, retired);
+        let count = self.retired_count.fetch_add(1, Ordering::Relaxed) + 1;
+        // Scanning is proportional to the number of slots, so wait for at
+        // least that many retired nodes to keep the cost per node constant.
+        if count >= RECLAIM_THRESHOLD.max(2 * self.slot_count.load(Ordering::Relaxed)) {
+            self.reclaim();
+        }
+    }
+
+    /// Free every retired node that isn't protected by a hazard pointer.
+    pub fn reclaim(&self) {
+        // Take the whole list, so concurrent reclaimers don't see the same
+        // nodes. Acquire matches the Release in `push_retired`, which makes
+        // the unlinking of the nodes happen before our scan.
+        let mut retired = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        if retired.is_null() {
+            return;
+        }
+        // Pairs with the fence in `protect`: either the reader sees that the
+        // node was unlinked and doesn't use it, or we see its hazard.
+        fence(Ordering::SeqCst);
+        let protected: HashSet<*mut ()> = self
+            .slots()
+            .map(|slot| slot.hazard.load(Ordering::Acquire))
+            .filter(|hazard| !hazard.is_null())
+            .collect();
+
+        let mut kept_head: *mut Retired = ptr::null_mut();
+        let mut kept_tail: *mut Retired = ptr::null_mut();
+        let mut freed = 0;
+        while !retired.is_null() {
+            // Safety: We took this list, so we have exclusive access to it.
+            let node = unsafe { &mut *retired };
+            retired = node.next;
+            if protected.contains(&node.ptr) {
+                node.next = kept_head;
+                kept_head = node;
+                if kept_tail.is_null() {
+                    kept_tail = node;
+                }
+            } else {
+                unsafe {
+                    (node.drop)(node.ptr);
+                    drop(Box::from_raw(node as *mut Retired));
+                }
+                freed += 1;
+            }
+        }
+        self.retired_count.fetch_sub(freed, Ordering::Relaxed);
+        if !kept_head.is_null() {
+            self.push_retired(kept_head, kept_tail);
+        }
+    }
+
+    /// Push the list from `head` to `tail` onto the retired stack.
+    fn push_retired(&self, head: *mut Retired, tail: *mut Retired) {
+        let mut old = self.retired.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*tail).next = old };
+            match self.retired.compare_exchange_weak(
+                old,
+                head,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(o) => old = o,
+            }
+        }
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        let mut slot = self.slots.load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            let s = unsafe { slot.as_ref()? };
+            slot = s.next;
+            Some(s)
+        })
+    }
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        // No HazardPointers can outlive the domain, so nothing is protected
+        // any more.
+        let mut retired = *self.retired.get_mut();
+        while !retired.is_null() {
+            let node = unsafe { Box::from_raw(retired) };
+            unsafe { (node.drop)(node.ptr) };
+            retired = node.next;
+        }
+        let mut slot = *self.slots.get_mut();
+        while !slot.is_null() {
+            let s = unsafe { Box::from_raw(slot) };
+            slot = s.next;
+        }
+    }
+}
+
+impl HazardPointer<'_> {
+    /// Load the pointer in `src` and protect it, so that it won't be freed
+    /// until this hazard pointer is reset, protects something else, or is
+    /// dropped.
+    ///
+    /// This only protects the pointer if it's retired to the same domain as
+    /// this hazard pointer was acquired from.
+    pub fn protect<T>(&mut self, src: &AtomicPtr<T>) -> *mut T {
+        let mut ptr = src.load(Ordering::Relaxed);
+        loop {
+            // Release as in `reset`: this may replace a pointer we protected
+            // before, and our use of that node must happen before it's freed.
+            self.slot.hazard.store(ptr as *mut (), Ordering::Release);
+            // Our hazard must be visible before we check that the pointer is
+            // still in `src`, otherwise a reclaimer could miss it after the
+            // node was unlinked. Only a SeqCst fence orders a store before a
+            // later load.
+            fence(Ordering::SeqCst);
+            // Acquire to see the contents of the node it points to.
+            let current = src.load(Ordering::Acquire);
+            if current == ptr {
+                return ptr;
+            }
+            // It changed in between, so the node might have been retired
+            // before we announced it. Try again with the new one.
+            ptr = current;
+        }
+    }
+
+    /// Stop protecting the current pointer, if any.
+    pub fn reset(&mut self) {
+        // Release so our use of the node happens before it's freed.
+        self.slot.hazard.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+impl Drop for HazardPointer<'_> {
+    fn drop(&mut self) {
+        self.reset();
+        self.slot.active.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn protected_until_reset() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let domain = Domain::new();
+        let src = AtomicPtr::new(Box::into_raw(Box::new(DetectDrop)));
+        let mut hazard = domain.hazard_pointer();
+        let node = hazard.protect(&src);
+
+        // Unlink and retire it while it's still protected.
+        src.store(ptr::null_mut(), Ordering::Relaxed);
+        unsafe { domain.retire(node) };
+        domain.reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+
+        hazard.reset();
+        domain.reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn retired_nodes_freed() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Node {
+            next: *mut Node,
+        }
+
+        impl Drop for Node {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // A Treiber stack of empty nodes, as in the module example.
+        let domain = Domain::new();
+        let head = AtomicPtr::<Node>::new(ptr::null_mut());
+        let push = || {
+            let node = Box::into_raw(Box::new(Node {
+                next: ptr::null_mut(),
+            }));
+            let mut old = head.load(Ordering::Relaxed);
+            loop {
+                unsafe { (*node).next = old };
+                match head.compare_exchange_weak(old, node, Ordering::Release, Ordering::Relaxed) {
+                    Ok(_) => return,
+                    Err(h) => old = h,
+                }
+            }
+        };
+        let pop = || {
+            let mut hazard = domain.hazard_pointer();
+            loop {
+                let node = hazard.protect(&head);
+                assert!(!node.is_null());
+                let next = unsafe { (*node).next };
+                if head
+                    .compare_exchange(node, next, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    hazard.reset();
+                    unsafe { domain.retire(node) };
+                    return;
+                }
+            }
+        };
+
+        let (threads, n) = if cfg!(miri) { (3, 50) } else { (4, 10_000) };
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..n {
+                        push();
+                        pop();
+                    }
+                });
+            }
+        });
+
+        // Enough was retired for nodes to have been freed along the way, and
+        // with nothing protected any more the rest go now.
+        assert!(NUM_DROPS.load(Ordering::Relaxed) > 0);
+        domain.reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), threads * n);
+    }
+}
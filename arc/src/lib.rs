@@ -1 +1,12 @@
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+pub mod atomic_arc;
+pub mod biased_arc;
+pub mod debug_refs;
+pub mod hazard;
+pub mod mapped_arc;
+pub mod once_arc;
+pub mod rc;
+pub mod rcu;
 pub mod simple_arc;
+pub mod thin_arc;
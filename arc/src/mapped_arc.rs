@@ -0,0 +1,105 @@
+use std::fmt;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::simple_arc::Arc;
+
+/// An [`Arc`] to a part of the data, such as one field of a larger struct,
+/// created by [`Arc::map`].
+///
+/// It holds a strong reference to the whole of the data, so it counts
+/// towards [`Arc::strong_count`] and keeps everything alive, but only
+/// dereferences to the part it was mapped to.
+///
+/// ```
+/// use arc::simple_arc::Arc;
+///
+/// struct Config {
+///     name: String,
+///     port: u16,
+/// }
+///
+/// let config = Arc::new(Config { name: "server".into(), port: 80 });
+/// let name = Arc::map(config.clone(), |c| &c.name);
+/// assert_eq!(*name, "server");
+/// assert_eq!(Arc::strong_count(&config), 2);
+/// drop(config);
+/// // The Config is still alive, held by `name`.
+/// assert_eq!(name.len(), 6);
+/// ```
+pub struct MappedArc<T: ?Sized, U: ?Sized, A: Allocator = Global> {
+    arc: Arc<T, A>,
+    /// Points into the data of `arc`, so is valid for as long as it is.
+    ptr: NonNull<U>,
+}
+
+// Like an Arc<U>, but the whole of T is shared along with it.
+unsafe impl<T: ?Sized, U: ?Sized + Sync, A: Allocator> Send for MappedArc<T, U, A> where
+    Arc<T, A>: Send
+{
+}
+unsafe impl<T: ?Sized, U: ?Sized + Sync, A: Allocator> Sync for MappedArc<T, U, A> where
+    Arc<T, A>: Sync
+{
+}
+
+impl<T: ?Sized, U: ?Sized, A: Allocator> MappedArc<T, U, A> {
+    /// See [`Arc::map`].
+    pub fn new(arc: Arc<T, A>, f: impl FnOnce(&T) -> &U) -> Self {
+        let ptr = NonNull::from(f(&arc));
+        Self { arc, ptr }
+    }
+
+    /// Narrow this down further, to a part of `U`.
+    pub fn map<V: ?Sized>(mapped: Self, f: impl FnOnce(&U) -> &V) -> MappedArc<T, V, A> {
+        let ptr = NonNull::from(f(&mapped));
+        MappedArc {
+            arc: mapped.arc,
+            ptr,
+        }
+    }
+
+    /// The [`Arc`] to the whole of the data.
+    pub fn parent(mapped: &Self) -> &Arc<T, A> {
+        &mapped.arc
+    }
+
+    /// Give up the mapping, keeping the [`Arc`] to the whole of the data.
+    pub fn into_parent(mapped: Self) -> Arc<T, A> {
+        mapped.arc
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, A: Allocator> Deref for MappedArc<T, U, A> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        // Safety: The parent Arc keeps the data we point into alive.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+// Counting is all done by the parent Arc, which is also what drops the data
+// when the last of them goes.
+impl<T: ?Sized, U: ?Sized, A: Allocator + Clone> Clone for MappedArc<T, U, A> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn clone(&self) -> Self {
+        Self {
+            arc: self.arc.clone(),
+            ptr: self.ptr,
+        }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for MappedArc<T, U, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized, U: ?Sized + fmt::Display, A: Allocator> fmt::Display for MappedArc<T, U, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
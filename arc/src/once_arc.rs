@@ -0,0 +1,166 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+use atomic_wait::{wait, wake_all};
+
+use crate::simple_arc::Arc;
+
+// The states of the initialisation, in `OnceArc::state`.
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+// As RUNNING, but with threads waiting on the futex that need waking.
+const RUNNING_WAITING: u32 = 2;
+const COMPLETE: u32 = 3;
+
+/// An [`Arc`] which is created on first use, for lazily initialised shared
+/// values.
+///
+/// The initialiser runs exactly once, on whichever thread gets there first.
+/// Other threads asking for the value in the meantime are put to sleep on a
+/// futex (as with `lock::Mutex`) rather than running an initialiser of their
+/// own, and are handed a clone of the same [`Arc`] once it's done.
+///
+/// ```
+/// use arc::once_arc::OnceArc;
+///
+/// static CONFIG: OnceArc<String> = OnceArc::new();
+///
+/// let config = CONFIG.get_or_init(|| "expensive".to_string());
+/// assert_eq!(*config, "expensive");
+/// assert!(arc::simple_arc::Arc::ptr_eq(&config, &CONFIG.get().unwrap()));
+/// ```
+pub struct OnceArc<T> {
+    /// Pointer from [`Arc::into_raw`] once initialised, the [`OnceArc`] owns
+    /// one strong reference.
+    ptr: AtomicPtr<T>,
+    state: AtomicU32,
+    /// Sharing a [`OnceArc`] shares the [`Arc`] inside it, so it may only be
+    /// [`Send`] or [`Sync`] when that [`Arc`] is.
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> OnceArc<T> {
+    pub const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+            state: AtomicU32::new(INCOMPLETE),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get a clone of the [`Arc`], if it has been initialised.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn get(&self) -> Option<Arc<T>> {
+        // Acquire matches the Release store in `get_or_init`, so that we see
+        // the initialised data.
+        let ptr = self.ptr.load(Ordering::Acquire);
+        if ptr.is_null() {
+            return None;
+        }
+        // Safety: We own a strong reference until we're dropped, which can't
+        // happen while we're borrowed.
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            Some(Arc::from_raw(ptr))
+        }
+    }
+
+    /// Get a clone of the [`Arc`], initialising it with `f` if this is the
+    /// first call.
+    ///
+    /// If `f` panics, the panic is passed on and the next caller will try to
+    /// initialise it again.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> Arc<T> {
+        let mut f = Some(f);
+        loop {
+            if let Some(arc) = self.get() {
+                return arc;
+            }
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    // Should `f` panic, let the next thread have a go instead
+                    // of leaving everyone waiting forever.
+                    let reset = ResetOnPanic(&self.state);
+                    let arc = Arc::new((f.take().unwrap())());
+                    std::mem::forget(reset);
+                    // Release so that `get` sees the initialised data.
+                    self.ptr
+                        .store(Arc::into_raw(arc) as *mut T, Ordering::Release);
+                    if self.state.swap(COMPLETE, Ordering::Release) == RUNNING_WAITING {
+                        wake_all(&self.state);
+                    }
+                }
+                Err(COMPLETE) => {}
+                Err(state) => {
+                    // Mark that there is someone to wake before going to
+                    // sleep. Should the state have changed in the meantime,
+                    // start over.
+                    if state == RUNNING_WAITING
+                        || self
+                            .state
+                            .compare_exchange(
+                                RUNNING,
+                                RUNNING_WAITING,
+                                Ordering::Relaxed,
+                                Ordering::Relaxed,
+                            )
+                            .is_ok()
+                    {
+                        wait(&self.state, RUNNING_WAITING);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> Default for OnceArc<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceArc<T> {
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            // Safety: Give up the reference owned by us.
+            unsafe { drop(Arc::from_raw(ptr)) }
+        }
+    }
+}
+
+/// Puts the state back to [`INCOMPLETE`] if the initialiser unwinds.
+struct ResetOnPanic<'a>(&'a AtomicU32);
+
+impl Drop for ResetOnPanic<'_> {
+    fn drop(&mut self) {
+        if self.0.swap(INCOMPLETE, Ordering::Relaxed) == RUNNING_WAITING {
+            wake_all(self.0);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "debug-refs"))]
+mod tests {
+    use super::*;
+    use crate::debug_refs::held_from;
+
+    #[test]
+    fn get_records_caller() {
+        let once = OnceArc::new();
+        let (init, init_line) = (once.get_or_init(|| 1), line!());
+        let (got, get_line) = (once.get().unwrap(), line!());
+        assert!(held_from(file!(), init_line));
+        assert!(held_from(file!(), get_line));
+        drop((init, got));
+        assert!(!held_from(file!(), init_line));
+        assert!(!held_from(file!(), get_line));
+    }
+}
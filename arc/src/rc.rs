@@ -0,0 +1,371 @@
+use std::alloc::Layout;
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicUsize;
+
+use crate::simple_arc::Arc;
+
+/// The same layout as the header of a `simple_arc::Arc`, only with plain
+/// counters in place of atomic ones. A unique [`Rc`] or [`Arc`] can then be
+/// turned into the other in place, by reinterpreting the counters.
+#[repr(C)]
+struct RcData<T> {
+    /// Number of [`Rc`]s.
+    data_ref_count: Cell<usize>,
+    /// Number of [`Weak`]s, plus one if there are any [`Rc`]s.
+    alloc_ref_count: Cell<usize>,
+    data: UnsafeCell<ManuallyDrop<T>>,
+}
+
+// The in-place conversions rely on the counters being interchangeable.
+const _: () = assert!(
+    std::mem::size_of::<Cell<usize>>() == std::mem::size_of::<AtomicUsize>()
+        && std::mem::align_of::<Cell<usize>>() == std::mem::align_of::<AtomicUsize>()
+);
+
+/// A single-threaded reference counted pointer, the non-atomic sibling of
+/// `simple_arc::Arc`.
+///
+/// The counting works exactly the same, but with [`Cell`]s instead of
+/// atomics. Only the core of the [`Arc`] API is mirrored: creating, cloning,
+/// [`Weak`]s, taking the data back out, and raw pointers. It is limited to
+/// sized `T`, so there is no unsizing, and nothing like `new_cyclic`, `pin`
+/// or custom allocators either.
+///
+/// Nothing stops two threads touching the same counter, other than
+/// [`Rc`] being neither [`Send`] nor [`Sync`], which it gets for free from
+/// the [`NonNull`] inside.
+pub struct Rc<T> {
+    ptr: NonNull<RcData<T>>,
+}
+
+/// A non-owning reference to the data within an [`Rc`].
+pub struct Weak<T> {
+    ptr: NonNull<RcData<T>>,
+}
+
+impl<T> Rc<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(RcData {
+                data_ref_count: Cell::new(1),
+                alloc_ref_count: Cell::new(1),
+                data: UnsafeCell::new(ManuallyDrop::new(data)),
+            }))),
+        }
+    }
+
+    fn data(&self) -> &RcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Whether this is the only reference of any kind to the allocation.
+    fn is_unique(rc: &Self) -> bool {
+        rc.data().data_ref_count.get() == 1 && rc.data().alloc_ref_count.get() == 1
+    }
+
+    pub fn get_mut(rc: &mut Self) -> Option<&mut T> {
+        // No need for the tricks that Arc uses here, as nothing else can
+        // create a Weak while we're looking.
+        if Self::is_unique(rc) {
+            unsafe { Some(&mut *rc.data().data.get()) }
+        } else {
+            None
+        }
+    }
+
+    /// Create a [`Weak`] pointer to the same allocation.
+    pub fn downgrade(rc: &Self) -> Weak<T> {
+        let count = &rc.data().alloc_ref_count;
+        count.set(count.get().checked_add(1).expect("weak count overflow"));
+        Weak { ptr: rc.ptr }
+    }
+
+    /// Number of [`Rc`]s pointing to this allocation.
+    pub fn strong_count(rc: &Self) -> usize {
+        rc.data().data_ref_count.get()
+    }
+
+    /// Number of [`Weak`]s pointing to this allocation.
+    pub fn weak_count(rc: &Self) -> usize {
+        rc.data().alloc_ref_count.get() - 1
+    }
+
+    /// Whether both [`Rc`]s point to the same allocation.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// Take the inner value out if this is the only [`Rc`], otherwise hand
+    /// the [`Rc`] back untouched.
+    pub fn try_unwrap(rc: Self) -> Result<T, Self> {
+        if Self::strong_count(&rc) != 1 {
+            return Err(rc);
+        }
+        rc.data().data_ref_count.set(0);
+        Ok(unsafe { Self::take_last(rc) })
+    }
+
+    /// Drop this [`Rc`], returning the inner value if it was the last one.
+    ///
+    /// Without other threads involved this is the same as
+    /// `Rc::try_unwrap(rc).ok()`, it exists to mirror `Arc::into_inner`.
+    pub fn into_inner(rc: Self) -> Option<T> {
+        Self::try_unwrap(rc).ok()
+    }
+
+    /// Like [`Rc::try_unwrap`], but clones the inner value when there are
+    /// other [`Rc`]s around.
+    pub fn unwrap_or_clone(rc: Self) -> T
+    where
+        T: Clone,
+    {
+        Self::try_unwrap(rc).unwrap_or_else(|rc| (*rc).clone())
+    }
+
+    /// Clone-on-write access to the inner value, see `Arc::make_mut`.
+    pub fn make_mut(rc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        if Self::strong_count(rc) != 1 {
+            *rc = Rc::new((**rc).clone());
+        } else if Self::weak_count(rc) != 0 {
+            // Move the data to a fresh allocation, leaving the Weaks behind.
+            rc.data().data_ref_count.set(0);
+            let data = unsafe { Self::take_last(ptr::read(rc)) };
+            unsafe { ptr::write(rc, Rc::new(data)) };
+        }
+        unsafe { &mut *rc.data().data.get() }
+    }
+
+    /// Move the data out of the final [`Rc`], whose data count has already
+    /// been brought to 0 by the caller.
+    unsafe fn take_last(rc: Self) -> T {
+        let rc = ManuallyDrop::new(rc);
+        let data = ManuallyDrop::take(&mut *rc.data().data.get());
+        drop(Weak { ptr: rc.ptr });
+        data
+    }
+
+    /// Pointer to the data, which stays valid for as long as there are
+    /// [`Rc`]s around.
+    pub fn as_ptr(rc: &Self) -> *const T {
+        unsafe { ptr::addr_of!((*rc.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// Consume the [`Rc`], returning a pointer to the data, see
+    /// `Arc::into_raw`.
+    pub fn into_raw(rc: Self) -> *const T {
+        let ptr = Self::as_ptr(&rc);
+        std::mem::forget(rc);
+        ptr
+    }
+
+    /// Reconstruct an [`Rc`] from a pointer returned by [`Rc::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`Rc::into_raw`], and each call takes over
+    /// one strong reference.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let offset = Layout::new::<RcData<()>>()
+            .extend(Layout::new::<T>())
+            .unwrap()
+            .1;
+        Self {
+            ptr: NonNull::new_unchecked((ptr as *mut u8).sub(offset) as *mut RcData<T>),
+        }
+    }
+
+    /// Turn this into an [`Arc`] in place, if it is the only [`Rc`] and
+    /// there are no [`Weak`]s. Otherwise the [`Rc`] is handed back untouched.
+    ///
+    /// The two share a layout, so this is only a matter of reinterpreting the
+    /// counters, which are both 1.
+    pub fn try_into_arc(rc: Self) -> Result<Arc<T>, Self> {
+        if !Self::is_unique(&rc) {
+            return Err(rc);
+        }
+        Ok(unsafe { Arc::from_raw(Self::into_raw(rc)) })
+    }
+}
+
+impl<T> Deref for Rc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.data().data.get() }
+    }
+}
+
+impl<T> Clone for Rc<T> {
+    fn clone(&self) -> Self {
+        let count = &self.data().data_ref_count;
+        // Unlike with Arc, other threads can't race us past an overflow check.
+        count.set(count.get().checked_add(1).expect("strong count overflow"));
+        Self { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for Rc<T> {
+    fn drop(&mut self) {
+        let count = &self.data().data_ref_count;
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            unsafe { ManuallyDrop::drop(&mut *self.data().data.get()) }
+            drop(Weak { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Weak<T> {
+    fn data(&self) -> &RcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Attempt to get an [`Rc`] from this [`Weak`].
+    ///
+    /// Returns [`None`] if the data has already been dropped.
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        let count = &self.data().data_ref_count;
+        if count.get() == 0 {
+            return None;
+        }
+        count.set(count.get().checked_add(1).expect("strong count overflow"));
+        Some(Rc { ptr: self.ptr })
+    }
+
+    /// Number of [`Rc`]s pointing to this allocation.
+    pub fn strong_count(&self) -> usize {
+        self.data().data_ref_count.get()
+    }
+
+    /// Number of [`Weak`]s pointing to this allocation, or 0 if there are no
+    /// [`Rc`]s left.
+    pub fn weak_count(&self) -> usize {
+        if self.strong_count() > 0 {
+            // Discount the single weak reference shared by all the Rcs.
+            self.data().alloc_ref_count.get() - 1
+        } else {
+            0
+        }
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        let count = &self.data().alloc_ref_count;
+        count.set(count.get().checked_add(1).expect("weak count overflow"));
+        Self { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        let count = &self.data().alloc_ref_count;
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Rc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Rc<T> {}
+
+impl<T: Hash> Hash for Rc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: Default> Default for Rc<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for Rc<T> {
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn arc_round_trip() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(i32);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let rc = Rc::new(DetectDrop(5));
+        let address = Rc::as_ptr(&rc);
+
+        // Not unique, so the Rc comes back.
+        let other = rc.clone();
+        let rc = Rc::try_into_arc(rc).err().unwrap();
+        drop(other);
+        let weak = Rc::downgrade(&rc);
+        let rc = Rc::try_into_arc(rc).err().unwrap();
+        drop(weak);
+
+        // The same allocation, now counted atomically.
+        let arc = Rc::try_into_arc(rc).ok().unwrap();
+        assert_eq!(Arc::as_ptr(&arc), address);
+        assert_eq!(Arc::strong_count(&arc), 1);
+        assert_eq!(Arc::weak_count(&arc), 0);
+
+        let other = arc.clone();
+        let arc = Arc::try_into_rc(arc).err().unwrap();
+        drop(other);
+        let weak = Arc::downgrade(&arc);
+        let arc = Arc::try_into_rc(arc).err().unwrap();
+        drop(weak);
+
+        // And back again, still without touching the data.
+        let rc = Arc::try_into_rc(arc).ok().unwrap();
+        assert_eq!(Rc::as_ptr(&rc), address);
+        assert_eq!(Rc::strong_count(&rc), 1);
+        assert_eq!(Rc::weak_count(&rc), 0);
+        assert_eq!(rc.0, 5);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+
+        // The counters still work after the trip.
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(weak.weak_count(), 1);
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+}
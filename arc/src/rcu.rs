@@ -0,0 +1,97 @@
+use crate::atomic_arc::AtomicArc;
+use crate::simple_arc::Arc;
+
+/// A read-copy-update cell: a value which is read often and replaced rarely.
+///
+/// Readers take a snapshot, an [`Arc`] to the current version, which stays
+/// the same for as long as they hold on to it. Writers never modify a version
+/// in place, but build a new one from a copy of the old and swap it in. Old
+/// versions are freed once the last reader drops its snapshot.
+///
+/// Unlike with a read-write lock, readers never wait on a writer, and a
+/// writer never waits for readers to finish with their snapshots.
+///
+/// ```
+/// use arc::rcu::RcuCell;
+///
+/// let routes = RcuCell::new(vec!["10.0.0.0/8"]);
+/// let before = routes.read();
+/// routes.update(|old| {
+///     let mut new = old.clone();
+///     new.push("192.168.0.0/16");
+///     new
+/// });
+/// assert_eq!(before.len(), 1);
+/// assert_eq!(routes.read().len(), 2);
+/// ```
+pub struct RcuCell<T> {
+    current: AtomicArc<T>,
+}
+
+impl<T> RcuCell<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            current: AtomicArc::new(Arc::new(data)),
+        }
+    }
+
+    /// Take a snapshot of the current version.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn read(&self) -> Arc<T> {
+        self.current.load()
+    }
+
+    /// Replace the current version with `data`, regardless of what it is.
+    pub fn write(&self, data: T) {
+        self.current.store(Arc::new(data));
+    }
+
+    /// Replace the current version with one built from it by `f`, returning
+    /// the version that was replaced.
+    ///
+    /// If another writer gets in between, `f` is called again on their
+    /// version, so that no update is lost. It should not have side effects.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn update(&self, mut f: impl FnMut(&T) -> T) -> Arc<T> {
+        let mut old = self.read();
+        loop {
+            match self.current.compare_and_swap(&old, Arc::new(f(&old))) {
+                Ok(replaced) => return replaced,
+                // Only the pointer is compared, so a version that has been
+                // replaced can't come back through a reused allocation: our
+                // snapshot keeps it alive.
+                Err(_) => old = self.read(),
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for RcuCell<T> {
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+#[cfg(all(test, feature = "debug-refs"))]
+mod tests {
+    use super::*;
+    use crate::debug_refs::held_from;
+
+    #[test]
+    fn read_records_caller() {
+        let cell = RcuCell::new(1);
+        let (snapshot, line) = (cell.read(), line!());
+        assert!(held_from(file!(), line));
+        let (old, update_line) = (cell.update(|x| x + 1), line!());
+        assert!(held_from(file!(), update_line));
+        drop((snapshot, old));
+        assert!(!held_from(file!(), line));
+        assert!(!held_from(file!(), update_line));
+    }
+}
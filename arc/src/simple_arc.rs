@@ -1,41 +1,654 @@
-use std::ops::Deref;
-use std::ptr::NonNull;
+use std::alloc::{handle_alloc_error, Layout};
+use std::borrow::Borrow;
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
 use std::sync::atomic::{fence, AtomicUsize, Ordering};
-use std::usize;
 
-struct ArcData<T> {
-    ref_count: AtomicUsize,
-    data: T,
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::debug_refs::Holder;
+use crate::mapped_arc::MappedArc;
+use crate::rc::Rc;
+
+// repr(C) fixes the counters at the start of the allocation, so that the
+// offset of `data` can be computed from its alignment alone. This is what
+// allows unsized data (slices, str, trait objects) to be allocated by hand.
+#[repr(C)]
+struct ArcData<T: ?Sized> {
+    /// Number of [`Arc`]s.
+    data_ref_count: AtomicUsize,
+    /// Number of [`Weak`]s, plus one if there are any [`Arc`]s.
+    ///
+    /// All of the [`Arc`]s share a single weak reference between them, which
+    /// means that a drop of an [`Arc`] only needs to touch this counter when
+    /// it is the last one.
+    alloc_ref_count: AtomicUsize,
+    /// The data. Dropped if there are only weak pointers left, which is why
+    /// it is wrapped in [`ManuallyDrop`]. The [`UnsafeCell`] is needed as the
+    /// last [`Arc`] must be able to drop it through a shared reference.
+    data: UnsafeCell<ManuallyDrop<T>>,
+}
+
+/// A thread-safe reference counted pointer, built up in chapter 6 of the book.
+///
+/// The counters live in a private header in front of the data. Their values
+/// can be read through associated functions such as [`Arc::strong_count`],
+/// but there is no way for a caller to reach the counters themselves:
+///
+/// ```compile_fail,E0624
+/// let a = arc::simple_arc::Arc::new(1);
+/// // The method which used to hand out the header is private.
+/// arc::simple_arc::Arc::data(&a);
+/// ```
+///
+/// ```compile_fail,E0603
+/// // As is the type of the header itself.
+/// let _: Option<&arc::simple_arc::ArcData<i32>> = None;
+/// ```
+///
+/// The allocation is made through `A`, which defaults to the global
+/// allocator. Use [`Arc::new_in`] to place it somewhere else, like an arena.
+pub struct Arc<T: ?Sized, A: Allocator = Global> {
+    ptr: NonNull<ArcData<T>>,
+    /// Every Arc and Weak carries the allocator, as whichever of them is the
+    /// last to go needs it to free the allocation.
+    alloc: A,
+    /// Records where this Arc was created with the `debug-refs` feature,
+    /// zero-sized otherwise.
+    holder: Holder,
 }
 
-pub struct Arc<T> {
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send> Send for Arc<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Sync> Sync for Arc<T, A> {}
+
+/// A non-owning reference to the data within an [`Arc`].
+///
+/// A [`Weak`] keeps the allocation alive, but not the data itself. This allows
+/// structures with back-pointers, such as a child node referring to its parent,
+/// without the reference cycle keeping everything alive forever.
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
     ptr: NonNull<ArcData<T>>,
+    alloc: A,
 }
 
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send> Send for Weak<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Sync> Sync for Weak<T, A> {}
+
+/// A uniquely owned [`Arc`], which can be mutated freely before it is shared.
+///
+/// This lives in the same allocation, with the same counters, as an [`Arc`].
+/// The counters are already set up for a single [`Arc`], so turning it into
+/// one with [`UniqueArc::shareable`] costs nothing. Until then it behaves like
+/// a [`Box`], with [`DerefMut`] and no atomic operations at all.
+pub struct UniqueArc<T: ?Sized> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+// Like Box, as there is only ever one owner.
+unsafe impl<T: ?Sized + Send> Send for UniqueArc<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for UniqueArc<T> {}
+
 impl<T> Arc<T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
     pub fn new(data: T) -> Self {
+        Self::new_in(data, Global)
+    }
+
+    /// Create a new pinned [`Arc`].
+    ///
+    /// The data of an [`Arc`] lives at the same address for as long as the
+    /// allocation does. It is only ever moved out by `try_unwrap`,
+    /// `into_inner`, `unwrap_or_clone` and `make_mut`, and those all need an
+    /// unpinned [`Arc`], which can't be had from a `Pin<Arc<T>>` unless
+    /// `T: Unpin`. A freshly created [`Arc`] has no other [`Arc`]s or
+    /// [`Weak`]s that could be used to get around this.
+    ///
+    /// ```
+    /// use arc::simple_arc::Arc;
+    ///
+    /// let a = Arc::pin(5);
+    /// let b = a.clone();
+    /// assert!(std::ptr::eq(&*a, &*b));
+    /// ```
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn pin(data: T) -> Pin<Self> {
+        unsafe { Pin::new_unchecked(Self::new(data)) }
+    }
+
+    /// Create a new [`Arc`] whose data holds a [`Weak`] pointer to itself.
+    ///
+    /// The closure is given a [`Weak`] to the allocation before the data
+    /// exists. Upgrading it fails until the closure has returned and the
+    /// [`Arc`] is complete.
+    ///
+    /// If the closure panics, the allocation is freed once the last of the
+    /// [`Weak`]s handed out during construction is gone.
+    ///
+    /// ```
+    /// use arc::simple_arc::{Arc, Weak};
+    ///
+    /// struct Node {
+    ///     me: Weak<Node>,
+    ///     value: i32,
+    /// }
+    ///
+    /// let node = Arc::new_cyclic(|me| Node {
+    ///     me: me.clone(),
+    ///     value: 5,
+    /// });
+    /// let again = node.me.upgrade().unwrap();
+    /// assert!(Arc::ptr_eq(&node, &again));
+    /// assert_eq!(again.value, 5);
+    /// ```
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn new_cyclic(data_fn: impl FnOnce(&Weak<T>) -> T) -> Self {
+        // A data count of 0 is what makes upgrade fail during construction.
+        // The weak count of 1 is the Weak we hand to the closure.
+        let uninit = Box::leak(Box::new(ArcData {
+            data_ref_count: AtomicUsize::new(0),
+            alloc_ref_count: AtomicUsize::new(1),
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+        }));
+        // MaybeUninit<T> has the same layout as T, and ArcData is repr(C).
+        let weak = Weak {
+            ptr: NonNull::from(uninit).cast::<ArcData<T>>(),
+            alloc: Global,
+        };
+        // On a panic, `weak` is dropped while unwinding. The data was never
+        // written, but as it's ManuallyDrop only the allocation is freed.
+        let data = data_fn(&weak);
+        unsafe { ptr::write(weak.data().data.get() as *mut T, data) };
+        // Release to publish the data to any Weak upgraded from here on.
+        weak.data().data_ref_count.store(1, Ordering::Release);
+        // The weak reference becomes the implicit one shared by all Arcs.
+        let weak = ManuallyDrop::new(weak);
         Self {
-            ptr: NonNull::from(Box::leak(Box::new(ArcData {
-                ref_count: AtomicUsize::new(0),
-                data,
-            }))),
+            ptr: weak.ptr,
+            alloc: Global,
+            holder: Holder::new(weak.ptr),
+        }
+    }
+
+    /// Turn this into an [`Rc`] in place, if it is the only [`Arc`] and
+    /// there are no [`Weak`]s. Otherwise the [`Arc`] is handed back untouched.
+    ///
+    /// The two share a layout, so this is only a matter of reinterpreting the
+    /// counters, which are both 1.
+    pub fn try_into_rc(mut arc: Self) -> Result<Rc<T>, Self> {
+        // The same check as get_mut, which also synchronises with any Arcs
+        // that were dropped on other threads.
+        if Self::get_mut(&mut arc).is_none() {
+            return Err(arc);
+        }
+        let ptr = Self::as_ptr(&arc);
+        // The reference now belongs to the Rc.
+        Self::into_parts(arc);
+        Ok(unsafe { Rc::from_raw(ptr) })
+    }
+
+    /// Turn an `Arc<T>` into an `Arc<U>` for an unsized `U`, typically a
+    /// trait object.
+    ///
+    /// The standard library does this implicitly through the unstable
+    /// `CoerceUnsized` trait. On stable we need the caller to perform the
+    /// coercion on a reference instead, which is where the compiler fills in
+    /// the metadata (vtable pointer or length) for us:
+    ///
+    /// ```
+    /// use arc::simple_arc::Arc;
+    /// use std::fmt::Debug;
+    ///
+    /// let a: Arc<dyn Debug> = Arc::unsize(Arc::new(5), |x| x as &dyn Debug);
+    /// ```
+    ///
+    /// Panics:
+    /// If the returned reference does not cover exactly the original value.
+    pub fn unsize<U: ?Sized>(arc: Self, f: impl FnOnce(&T) -> &U) -> Arc<U> {
+        let unsized_ref = f(&arc);
+        // Only the metadata of the new reference is trusted, so make sure it
+        // describes the same value that was allocated.
+        assert!(ptr::addr_eq(unsized_ref, &*arc));
+        assert_eq!(std::mem::size_of_val(unsized_ref), std::mem::size_of::<T>());
+        assert_eq!(
+            std::mem::align_of_val(unsized_ref),
+            std::mem::align_of::<T>()
+        );
+        let unsized_ptr = unsized_ref as *const U as *mut ArcData<U>;
+        let arc = ManuallyDrop::new(arc);
+        // Keep the metadata of the reference, but the address (and
+        // provenance) of our allocation, as the reference only covers the data.
+        let ptr = unsafe { set_data_ptr(unsized_ptr, arc.ptr.as_ptr() as *mut u8) };
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            alloc: Global,
+            // Still the same allocation, so the record carries over.
+            holder: unsafe { ptr::read(&arc.holder) },
         }
     }
+}
+
+impl<T, A: Allocator> Arc<T, A> {
+    /// Like [`Arc::new`], but allocating with `alloc` instead of the global
+    /// allocator.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn new_in(data: T, alloc: A) -> Self {
+        let layout = Layout::new::<ArcData<T>>();
+        let ptr = match alloc.allocate(layout) {
+            Ok(mem) => mem.cast::<ArcData<T>>(),
+            Err(_) => handle_alloc_error(layout),
+        };
+        unsafe {
+            ptr.as_ptr().write(ArcData {
+                data_ref_count: AtomicUsize::new(1),
+                alloc_ref_count: AtomicUsize::new(1),
+                data: UnsafeCell::new(ManuallyDrop::new(data)),
+            })
+        };
+        Self {
+            ptr,
+            alloc,
+            holder: Holder::new(ptr),
+        }
+    }
+
+    /// Take the inner value out if this is the only [`Arc`], otherwise hand
+    /// the [`Arc`] back untouched.
+    ///
+    /// Any remaining [`Weak`]s will fail to upgrade afterwards.
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        // Going from 1 to 0 means we were the last Arc, and because we own it
+        // nobody else can have cloned it in the meantime. A Weak cannot
+        // upgrade from 0 either.
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(arc);
+        }
+        // Same as in Drop, make sure all other uses of the data happen before
+        // we take it.
+        fence(Ordering::Acquire);
+        Ok(unsafe { Self::take_last(arc) })
+    }
+
+    /// Drop this [`Arc`], returning the inner value if it was the last one.
+    ///
+    /// Unlike `Arc::try_unwrap(arc).ok()`, two threads racing to call this on
+    /// the final two [`Arc`]s are guaranteed that exactly one of them gets
+    /// the value. With `try_unwrap` both could fail and the value would be
+    /// dropped instead.
+    pub fn into_inner(arc: Self) -> Option<T> {
+        if arc.data().data_ref_count.fetch_sub(1, Ordering::Release) != 1 {
+            // Our reference has been given up by the decrement already.
+            Self::into_parts(arc);
+            return None;
+        }
+        fence(Ordering::Acquire);
+        Some(unsafe { Self::take_last(arc) })
+    }
+
+    /// Like [`Arc::try_unwrap`], but clones the inner value when there are
+    /// other [`Arc`]s around.
+    pub fn unwrap_or_clone(arc: Self) -> T
+    where
+        T: Clone,
+    {
+        Self::try_unwrap(arc).unwrap_or_else(|arc| (*arc).clone())
+    }
+
+    /// Clone-on-write access to the inner value.
+    ///
+    /// If other [`Arc`]s exist the data is cloned into a fresh allocation
+    /// which this [`Arc`] then points to. If only [`Weak`]s exist then the
+    /// data is moved into a fresh allocation instead, disassociating those
+    /// [`Weak`]s. Otherwise this is the same as [`Arc::get_mut`].
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        // Temporarily dropping the count to 0 stops any Weak from upgrading
+        // while we inspect the weak count, as upgrade never goes from 0 to 1.
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Other Arcs exist, so we must clone.
+            *arc = Arc::new_in((**arc).clone(), arc.alloc.clone());
+        } else if arc.data().alloc_ref_count.load(Ordering::Relaxed) != 1 {
+            // We were the last Arc, but Weaks remain. Move the data out so
+            // that they can no longer upgrade to it, as the data count is now 0.
+            // Safety: The data count is 0, we have exclusive access.
+            let alloc = arc.alloc.clone();
+            let data = unsafe { Self::take_last(ptr::read(arc)) };
+            unsafe { ptr::write(arc, Arc::new_in(data, alloc)) };
+        } else {
+            // We are the only reference of any kind, restore the count.
+            arc.data().data_ref_count.store(1, Ordering::Release);
+        }
+        // Safety: Either way we now hold the only reference to the data.
+        unsafe { &mut *arc.data().data.get() }
+    }
+
+    /// Move the data out of the final [`Arc`], whose data count has already
+    /// been brought to 0 by the caller.
+    ///
+    /// Safety: The data count must be 0, with the caller having synchronised
+    /// with all other previous [`Arc`]s.
+    unsafe fn take_last(arc: Self) -> T {
+        let (ptr, alloc) = Self::into_parts(arc);
+        let data = ManuallyDrop::take(&mut *(*ptr.as_ptr()).data.get());
+        // Give up the implicit weak pointer shared by all Arcs, freeing the
+        // allocation when no other Weaks exist.
+        drop(Weak { ptr, alloc });
+        data
+    }
+}
 
-    pub fn data(&self) -> &ArcData<T> {
+impl<T: ?Sized, A: Allocator> Arc<T, A> {
+    fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
 
+    /// Take this [`Arc`] apart without touching the counters, for when its
+    /// reference is being handed over to something else.
+    fn into_parts(arc: Self) -> (NonNull<ArcData<T>>, A) {
+        let arc = ManuallyDrop::new(arc);
+        // The record of this Arc goes with it, whatever takes over.
+        unsafe {
+            let _: Holder = ptr::read(&arc.holder);
+            (arc.ptr, ptr::read(&arc.alloc))
+        }
+    }
+
     // arc: &mut Self is used here so that it must be called as Arc::get_mut(&mut value)
     // to avoid ambiguity with other methods on the underlying data (T).
     pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
-        if arc.data().ref_count.load(Ordering::Relaxed) == 1 {
-            fence(Ordering::Acquire);
-            // Nothing else can access the Arc here, there is only a single
-            // reference so this is safe to do
-            unsafe { Some(&mut arc.ptr.as_mut().data) }
-        } else {
-            None
+        // Acquire matches Weak::drop's Release decrement, to make sure any
+        // upgraded pointers are visible in the next data_ref_count.load.
+        //
+        // Temporarily "locking" the weak count with usize::MAX stops a
+        // concurrent downgrade from creating a Weak which could then be
+        // upgraded while we hand out the &mut T.
+        if arc
+            .data()
+            .alloc_ref_count
+            .compare_exchange(1, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let is_unique = arc.data().data_ref_count.load(Ordering::Relaxed) == 1;
+        // Release matches Acquire increment in `downgrade`, to make sure any
+        // changes to the data_ref_count that come after `downgrade` don't
+        // change the is_unique result above.
+        arc.data().alloc_ref_count.store(1, Ordering::Release);
+        if !is_unique {
+            return None;
+        }
+        // Acquire to match Arc::drop's Release decrement, to make sure nothing
+        // else is accessing the data.
+        fence(Ordering::Acquire);
+        unsafe { Some(&mut *arc.data().data.get()) }
+    }
+
+    /// Create a [`Weak`] pointer to the same allocation.
+    pub fn downgrade(arc: &Self) -> Weak<T, A>
+    where
+        A: Clone,
+    {
+        let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+        loop {
+            // The weak count is "locked" by get_mut, spin until it is released.
+            if n == usize::MAX {
+                std::hint::spin_loop();
+                n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+                continue;
+            }
+            assert!(n < usize::MAX - 1);
+            // Acquire synchronises with get_mut's release-store.
+            if let Err(e) = arc.data().alloc_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                n = e;
+                continue;
+            }
+            return Weak {
+                ptr: arc.ptr,
+                alloc: arc.alloc.clone(),
+            };
+        }
+    }
+
+    /// Number of [`Arc`]s pointing to this allocation.
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data().data_ref_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of [`Weak`]s pointing to this allocation.
+    pub fn weak_count(arc: &Self) -> usize {
+        match arc.data().alloc_ref_count.load(Ordering::Relaxed) {
+            // Locked by get_mut, which only happens when there are no Weaks.
+            usize::MAX => 0,
+            // Discount the single weak reference shared by all the Arcs.
+            n => n - 1,
+        }
+    }
+
+    /// Whether both [`Arc`]s point to the same allocation, rather than only
+    /// to equal values.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        ptr::addr_eq(this.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+
+    /// Pointer to the data, which stays valid for as long as there are
+    /// [`Arc`]s around.
+    pub fn as_ptr(arc: &Self) -> *const T {
+        // Going through addr_of! rather than Deref keeps the provenance of
+        // the whole allocation, which from_raw relies on to find the header.
+        unsafe { ptr::addr_of!((*arc.ptr.as_ptr()).data) as *const T }
+    }
+
+    /// The allocator the data was allocated with.
+    pub fn allocator(arc: &Self) -> &A {
+        &arc.alloc
+    }
+
+    /// Project this [`Arc`] onto a part of the data, typically a field.
+    ///
+    /// The result keeps this [`Arc`] inside it, so the whole of the data
+    /// lives for as long as the part does. See [`MappedArc`].
+    pub fn map<U: ?Sized>(arc: Self, f: impl FnOnce(&T) -> &U) -> MappedArc<T, U, A> {
+        MappedArc::new(arc, f)
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    /// Pin this [`Arc`] if it is the only [`Arc`] and there are no [`Weak`]s,
+    /// otherwise hand the [`Arc`] back untouched.
+    ///
+    /// Any other [`Arc`] or [`Weak`] would still be unpinned, and could be
+    /// used to move the data out once the pinned ones are gone. This is why,
+    /// unlike `Box::into_pin`, it can't be done unconditionally.
+    pub fn try_into_pin(arc: Self) -> Result<Pin<Self>, Self> {
+        Self::try_into_unique(arc).map(UniqueArc::into_pin)
+    }
+
+    /// Turn this into a [`UniqueArc`] if it is the only [`Arc`] and there are
+    /// no [`Weak`]s, otherwise hand the [`Arc`] back untouched.
+    pub fn try_into_unique(mut arc: Self) -> Result<UniqueArc<T>, Self> {
+        // The same check as get_mut. Once it passes nothing else can get at
+        // the allocation, as we own the only reference of any kind.
+        if Self::get_mut(&mut arc).is_none() {
+            return Err(arc);
+        }
+        Ok(UniqueArc {
+            ptr: Self::into_parts(arc).0,
+        })
+    }
+
+    /// Consume the [`Arc`], returning a pointer to the data.
+    ///
+    /// The reference count is not decremented, so to avoid a leak the
+    /// pointer must be turned back into an [`Arc`] with [`Arc::from_raw`].
+    /// The pointer is to the data itself, not the counters in front of it,
+    /// so it can be handed to C code as a plain `*const T`.
+    pub fn into_raw(arc: Self) -> *const T {
+        let ptr = Self::as_ptr(&arc);
+        let arc = ManuallyDrop::new(arc);
+        unsafe { ptr::read(&arc.holder) }.into_raw();
+        ptr
+    }
+
+    /// Reconstruct an [`Arc`] from a pointer returned by [`Arc::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`Arc::into_raw`] (on an `Arc<T>`
+    /// with the same `T`), and each call takes over one strong reference, so
+    /// it may only be called as many times as that reference was given up.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let ptr = NonNull::new_unchecked(Self::header_from_data(ptr));
+        Self {
+            ptr,
+            alloc: Global,
+            holder: Holder::from_raw(ptr),
+        }
+    }
+
+    /// Increment the strong count of the [`Arc`] behind a pointer returned by
+    /// [`Arc::into_raw`], e.g. when a C callback hands out another copy.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`Arc::into_raw`], and the
+    /// allocation must still have at least one strong reference.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // Clone and turn both back into raw pointers, so only the count
+        // changes.
+        let arc = Self::from_raw(ptr);
+        Self::into_raw(arc.clone());
+        Self::into_raw(arc);
+    }
+
+    /// Decrement the strong count of the [`Arc`] behind a pointer returned by
+    /// [`Arc::into_raw`], dropping the data if it was the last one.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`Arc::into_raw`], and the strong
+    /// reference given up here must have been one held through `ptr`.
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(Self::from_raw(ptr));
+    }
+
+    /// Walk back from a pointer to the data to the [`ArcData`] it lives in.
+    ///
+    /// Safety: `ptr` must point to the data of a live [`ArcData`].
+    unsafe fn header_from_data(ptr: *const T) -> *mut ArcData<T> {
+        // The offset of the data depends only on its alignment, thanks to
+        // the repr(C) on ArcData. For unsized data the alignment comes from
+        // the metadata, e.g. the vtable of a trait object.
+        let align = std::mem::align_of_val(&*ptr);
+        let offset = Layout::new::<ArcData<()>>()
+            .extend(Layout::from_size_align_unchecked(0, align))
+            .unwrap()
+            .1;
+        set_data_ptr(ptr as *mut ArcData<T>, (ptr as *mut u8).sub(offset))
+    }
+
+    /// Allocate an [`ArcData`] with room for a value of `value_layout`, with
+    /// both counters initialised but the data left for the caller to write.
+    ///
+    /// `mem_to_arcdata` turns the untyped allocation into a (possibly fat)
+    /// pointer, as only the caller knows the metadata for unsized data.
+    unsafe fn allocate_for_layout(
+        value_layout: Layout,
+        mem_to_arcdata: impl FnOnce(*mut u8) -> *mut ArcData<T>,
+    ) -> *mut ArcData<T> {
+        // The header is the two counters, followed by the data with whatever
+        // padding its alignment requires.
+        let layout = Layout::new::<ArcData<()>>()
+            .extend(value_layout)
+            .unwrap()
+            .0
+            .pad_to_align();
+        let mem = std::alloc::alloc(layout);
+        if mem.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        let inner = mem_to_arcdata(mem);
+        debug_assert_eq!(Layout::for_value(&*inner), layout);
+        ptr::addr_of_mut!((*inner).data_ref_count).write(AtomicUsize::new(1));
+        ptr::addr_of_mut!((*inner).alloc_ref_count).write(AtomicUsize::new(1));
+        inner
+    }
+
+    /// Allocate an [`Arc`] for a value of `value_layout`, and initialise the
+    /// value in place. This is for unsized types which can't be built
+    /// anywhere else first, such as a header followed by a slice.
+    ///
+    /// `mem_to_ptr` turns an address into a pointer to the value, attaching
+    /// the metadata (e.g. a slice length).
+    ///
+    /// Safety: The value described by `mem_to_ptr` must have the layout
+    /// `value_layout`, and `init` must fully initialise it. If `init` panics
+    /// the allocation is leaked.
+    pub(crate) unsafe fn new_in_place(
+        value_layout: Layout,
+        mem_to_ptr: impl FnOnce(*mut u8) -> *mut T,
+        init: impl FnOnce(*mut T),
+    ) -> Self {
+        // The pointer is to the start of the allocation, so it is the header
+        // and not the data that ends up at that address.
+        let inner =
+            Self::allocate_for_layout(value_layout, |mem| mem_to_ptr(mem) as *mut ArcData<T>);
+        init(ptr::addr_of_mut!((*inner).data) as *mut T);
+        let ptr = NonNull::new_unchecked(inner);
+        Self {
+            ptr,
+            alloc: Global,
+            holder: Holder::new(ptr),
+        }
+    }
+
+    /// Allocate an [`ArcData`] and move the bytes of `value` into it.
+    ///
+    /// Safety: `value` must be valid for reads, and must no longer be used
+    /// (or dropped) by the caller afterwards.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    unsafe fn copy_from_ptr(value: *const T) -> Self {
+        let size = std::mem::size_of_val(&*value);
+        let inner = Self::allocate_for_layout(Layout::for_value(&*value), |mem| {
+            set_data_ptr(value as *mut ArcData<T>, mem)
+        });
+        ptr::copy_nonoverlapping(
+            value as *const u8,
+            ptr::addr_of_mut!((*inner).data) as *mut u8,
+            size,
+        );
+        let ptr = NonNull::new_unchecked(inner);
+        Self {
+            ptr,
+            alloc: Global,
+            holder: Holder::new(ptr),
         }
     }
 }
@@ -44,33 +657,838 @@ impl<T> Arc<T> {
 // We cannot implement DerefMut here because Arc is shared ownership, not exclusive
 // ownership. If we have DerefMut here, the structure could be altered by another
 // referenced Arc.
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized, A: Allocator> Deref for Arc<T, A> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        &self.data().data
+        // Safety: Since there's an Arc to the data, the data exists and may be
+        // shared.
+        unsafe { &*self.data().data.get() }
     }
 }
 
 // Clone provides the same data pointer, but we atomically increment the reference count.
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized, A: Allocator + Clone> Clone for Arc<T, A> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
     fn clone(&self) -> Self {
-        if self.data().ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+        if self.data().data_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
             std::process::abort();
         }
-        Self { ptr: self.ptr }
+        Self {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            holder: Holder::new(self.ptr),
+        }
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized, A: Allocator> Drop for Arc<T, A> {
     fn drop(&mut self) {
-        if self.data().ref_count.fetch_sub(1, Ordering::Release) == 1 {
+        if self.data().data_ref_count.fetch_sub(1, Ordering::Release) == 1 {
             fence(Ordering::Acquire);
-            // from_raw reclaims exclusive ownership so that we can drop the full
-            // structure. We can only do this knowing we have the final reference.
-            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) }
+            // Safety: The data reference counter is zero, so nothing will
+            // access the data anymore.
+            unsafe { ManuallyDrop::drop(&mut *self.data().data.get()) }
+            // Now that there's no `Arc<T>`s left, drop the implicit weak
+            // pointer that represented all `Arc<T>`s. This frees the allocation
+            // if there are no other Weaks around. A reference to the allocator
+            // is itself an allocator, which saves us from needing a clone.
+            drop(Weak {
+                ptr: self.ptr,
+                alloc: &self.alloc,
+            });
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Attempt to get an [`Arc`] from this [`Weak`].
+    ///
+    /// Returns [`None`] if the data has already been dropped, i.e. there are
+    /// no [`Arc`]s left.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn upgrade(&self) -> Option<Arc<T, A>>
+    where
+        A: Clone,
+    {
+        let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
+        loop {
+            // We must never go from 0 back to 1, the data is gone at that point.
+            if n == 0 {
+                return None;
+            }
+            assert!(n < usize::MAX);
+            // Acquire on success matches the Release store at the end of
+            // `new_cyclic`, making the freshly written data visible.
+            if let Err(e) = self.data().data_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                n = e;
+                continue;
+            }
+            return Some(Arc {
+                ptr: self.ptr,
+                alloc: self.alloc.clone(),
+                holder: Holder::new(self.ptr),
+            });
+        }
+    }
+
+    /// Number of [`Arc`]s pointing to this allocation.
+    pub fn strong_count(&self) -> usize {
+        self.data().data_ref_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of [`Weak`]s pointing to this allocation, or 0 if there are no
+    /// [`Arc`]s left.
+    pub fn weak_count(&self) -> usize {
+        let weak = self.data().alloc_ref_count.load(Ordering::Relaxed);
+        if self.strong_count() > 0 {
+            // Discount the single weak reference shared by all the Arcs.
+            weak - 1
+        } else {
+            0
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone> Clone for Weak<T, A> {
+    fn clone(&self) -> Self {
+        if self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        Self {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
+    fn drop(&mut self) {
+        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            // Only the allocation is freed here, the data has already been
+            // dropped by the last Arc (hence the ManuallyDrop).
+            unsafe {
+                let layout = Layout::for_value(self.ptr.as_ref());
+                self.alloc.deallocate(self.ptr.cast(), layout);
+            }
+        }
+    }
+}
+
+impl<T> UniqueArc<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            ptr: Arc::into_parts(Arc::new(data)).0,
+        }
+    }
+}
+
+impl<T: ?Sized> UniqueArc<T> {
+    /// Share the data, turning this into an [`Arc`] without touching the
+    /// counters.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn shareable(unique: Self) -> Arc<T> {
+        let unique = ManuallyDrop::new(unique);
+        Arc {
+            ptr: unique.ptr,
+            alloc: Global,
+            holder: Holder::new(unique.ptr),
+        }
+    }
+
+    /// Share the data as a pinned [`Arc`].
+    ///
+    /// This is always fine, as a [`UniqueArc`] has no other [`Arc`]s or
+    /// [`Weak`]s that could later move the data.
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    pub fn into_pin(unique: Self) -> Pin<Arc<T>> {
+        unsafe { Pin::new_unchecked(Self::shareable(unique)) }
+    }
+}
+
+impl<T: ?Sized> Deref for UniqueArc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr.as_ref().data.get() }
+    }
+}
+
+// Unlike for Arc, DerefMut is fine here as we have exclusive ownership.
+impl<T: ?Sized> DerefMut for UniqueArc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr.as_ref().data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for UniqueArc<T> {
+    fn drop(&mut self) {
+        // We are the only reference, so there is no need to look at the
+        // counters. Drop the data and free the allocation straight away.
+        unsafe {
+            ManuallyDrop::drop(&mut *self.ptr.as_ref().data.get());
+            drop(Box::from_raw(self.ptr.as_ptr()));
+        }
+    }
+}
+
+/// Replace the address of a (possibly fat) pointer, keeping its metadata.
+///
+/// This is what the unstable `ptr::with_metadata_of` does, and how the
+/// standard library did it before that existed: fat pointers are laid out
+/// with the data pointer first, followed by the metadata.
+unsafe fn set_data_ptr<T: ?Sized>(mut ptr: *mut T, data: *mut u8) -> *mut T {
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, data);
+    ptr
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(mut v: Vec<T>) -> Self {
+        unsafe {
+            let arc = Self::copy_from_ptr(v.as_slice());
+            // The elements have been moved into the Arc, so only free the
+            // buffer of the Vec.
+            v.set_len(0);
+            arc
+        }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(v: &[T]) -> Self {
+        Self::from(v.to_vec())
+    }
+}
+
+impl From<&str> for Arc<str> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(v: &str) -> Self {
+        let arc = Arc::<[u8]>::from(v.as_bytes());
+        let arc = ManuallyDrop::new(arc);
+        // str has the same layout and metadata (the length) as [u8], and the
+        // bytes came from a str so are valid UTF-8.
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(arc.ptr.as_ptr() as *mut ArcData<str>) },
+            alloc: Global,
+            holder: unsafe { ptr::read(&arc.holder) },
+        }
+    }
+}
+
+impl From<String> for Arc<str> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(v: String) -> Self {
+        Self::from(v.as_str())
+    }
+}
+
+impl<T: ?Sized> From<Box<T>> for Arc<T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(v: Box<T>) -> Self {
+        unsafe {
+            let value = Box::into_raw(v);
+            let arc = Self::copy_from_ptr(value);
+            // The value has been moved into the Arc, so only free the Box's
+            // allocation without dropping its contents.
+            drop(Box::from_raw(value as *mut ManuallyDrop<T>));
+            arc
         }
     }
 }
 
-unsafe impl<T: Send + Sync> Send for Arc<T> {}
-unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+impl<T> FromIterator<T> for Arc<[T]> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+// The remaining traits all forward to the data, matching std::sync::Arc.
+
+impl<T> From<T> for Arc<T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator> fmt::Display for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// Prints the address of the data, not of the counters in front of it.
+impl<T: ?Sized, A: Allocator> fmt::Pointer for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&Self::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Arc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Arc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Arc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Arc<T, A> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, A: Allocator> Hash for Arc<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Borrow<T> for Arc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Arc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+// The data is never moved for as long as the allocation lives, no matter how
+// the Arc itself is moved around.
+impl<T: ?Sized, A: Allocator> Unpin for Arc<T, A> {}
+
+// The UnsafeCell in ArcData stops these being implemented automatically, but
+// it is only used for dropping the data, never for mutating it through a
+// shared Arc.
+impl<T: ?Sized + RefUnwindSafe, A: Allocator + UnwindSafe> UnwindSafe for Arc<T, A> {}
+impl<T: ?Sized + RefUnwindSafe, A: Allocator + UnwindSafe> RefUnwindSafe for Arc<T, A> {}
+impl<T: ?Sized + RefUnwindSafe, A: Allocator + UnwindSafe> UnwindSafe for Weak<T, A> {}
+impl<T: ?Sized + RefUnwindSafe, A: Allocator + UnwindSafe> RefUnwindSafe for Weak<T, A> {}
+
+#[cfg(feature = "serde")]
+impl<T: ?Sized + serde::Serialize, A: Allocator> serde::Serialize for Arc<T, A> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+// Deserializing creates a new allocation for every Arc, so any sharing in the
+// serialized data is lost.
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Arc<T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Not `.map(Self::new)`, which would lose the caller's location.
+        Ok(Self::new(T::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn drops_once_after_last_clone() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // Create an Arc with two clones and a Weak, one of them sent to
+        // another thread.
+        let x = Arc::new(("hello", DetectDrop));
+        let y = x.clone();
+        let z = Arc::downgrade(&x);
+
+        let t = thread::spawn(move || {
+            assert_eq!(x.0, "hello");
+        });
+
+        assert_eq!(y.0, "hello");
+        t.join().unwrap();
+
+        // One Arc, x, has been dropped by now. We still have y, so the object
+        // shouldn't have been dropped yet.
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert!(z.upgrade().is_some());
+
+        // Drop the remaining Arc.
+        drop(y);
+
+        // Now that y is dropped too, the object should have been dropped,
+        // exactly once, and the Weak can't bring it back.
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+        assert!(z.upgrade().is_none());
+        drop(z);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn make_mut_clones_when_shared() {
+        static NUM_CLONES: AtomicUsize = AtomicUsize::new(0);
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(i32);
+
+        impl Clone for DetectDrop {
+            fn clone(&self) -> Self {
+                NUM_CLONES.fetch_add(1, Ordering::Relaxed);
+                Self(self.0)
+            }
+        }
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let mut a = Arc::new(DetectDrop(1));
+        let b = a.clone();
+        Arc::make_mut(&mut a).0 = 2;
+
+        // The data was cloned, and `b` still sees the original.
+        assert_eq!(NUM_CLONES.load(Ordering::Relaxed), 1);
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!((a.0, b.0), (2, 1));
+        assert_eq!(Arc::strong_count(&a), 1);
+        assert_eq!(Arc::strong_count(&b), 1);
+
+        drop(a);
+        drop(b);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn make_mut_moves_when_only_weak() {
+        static NUM_CLONES: AtomicUsize = AtomicUsize::new(0);
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(i32);
+
+        impl Clone for DetectDrop {
+            fn clone(&self) -> Self {
+                NUM_CLONES.fetch_add(1, Ordering::Relaxed);
+                Self(self.0)
+            }
+        }
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let mut a = Arc::new(DetectDrop(1));
+        let w = Arc::downgrade(&a);
+        Arc::make_mut(&mut a).0 = 2;
+
+        // Moved rather than cloned, so nothing was cloned or dropped, and the
+        // Weak has been left behind with the old allocation.
+        assert_eq!(NUM_CLONES.load(Ordering::Relaxed), 0);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert_eq!(a.0, 2);
+        assert!(w.upgrade().is_none());
+        assert_eq!(Arc::weak_count(&a), 0);
+
+        drop(w);
+        drop(a);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn try_unwrap_when_shared() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let a = Arc::new(DetectDrop);
+        let b = a.clone();
+
+        // Shared, so the Arc is handed back untouched.
+        let a = Arc::try_unwrap(a).err().unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(Arc::strong_count(&a), 2);
+
+        // Once it's the last one, the value comes out without being dropped.
+        drop(b);
+        let value = Arc::try_unwrap(a).ok().unwrap();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(value);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn into_inner_race() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let rounds = if cfg!(miri) { 10 } else { 1000 };
+        for round in 1..=rounds {
+            let a = Arc::new(DetectDrop);
+            let b = a.clone();
+            let (a, b) = thread::scope(|s| {
+                let a = s.spawn(|| Arc::into_inner(a));
+                let b = s.spawn(|| Arc::into_inner(b));
+                (a.join().unwrap(), b.join().unwrap())
+            });
+            // Exactly one of the threads got the value, and nothing has been
+            // dropped behind its back.
+            assert!(a.is_some() != b.is_some());
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), round - 1);
+            drop((a, b));
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), round);
+        }
+    }
+
+    #[test]
+    fn new_cyclic_upgrade() {
+        struct Node {
+            me: Weak<Node>,
+        }
+
+        let node = Arc::new_cyclic(|me: &Weak<Node>| {
+            // The data doesn't exist yet, so there is nothing to upgrade to.
+            assert!(me.upgrade().is_none());
+            assert_eq!(me.strong_count(), 0);
+            Node { me: me.clone() }
+        });
+        let again = node.me.upgrade().unwrap();
+        assert!(Arc::ptr_eq(&node, &again));
+        assert_eq!(Arc::strong_count(&node), 2);
+        assert_eq!(Arc::weak_count(&node), 1);
+    }
+
+    #[test]
+    fn new_cyclic_panic() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // Smuggle a Weak out of the closure before it panics.
+        let stash = std::sync::Mutex::new(None);
+        let result = std::panic::catch_unwind(|| {
+            Arc::new_cyclic(|me: &Weak<DetectDrop>| {
+                *stash.lock().unwrap() = Some(me.clone());
+                panic!("no data for you");
+            })
+        });
+        assert!(result.is_err());
+
+        let weak = stash.into_inner().unwrap().unwrap();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+        // The data was never written, so there is nothing to drop.
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        // The Weak given to the closure is gone, leaving ours as the only
+        // reference to the allocation. Dropping it frees the allocation,
+        // which Miri's leak check confirms.
+        assert_eq!(weak.data().alloc_ref_count.load(Ordering::Relaxed), 1);
+        drop(weak);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn allocator_balanced() {
+        use allocator_api2::alloc::AllocError;
+
+        /// Counts the allocations currently live through it.
+        #[derive(Clone)]
+        struct Counting<'a>(&'a AtomicUsize);
+
+        unsafe impl Allocator for Counting<'_> {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+                Global.deallocate(ptr, layout)
+            }
+        }
+
+        let live = AtomicUsize::new(0);
+        let mut a = Arc::new_in(String::from("a"), Counting(&live));
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+
+        // Cloning the data for make_mut takes a second allocation, which the
+        // other Arc keeps.
+        let b = a.clone();
+        Arc::make_mut(&mut a).push('!');
+        assert_eq!(live.load(Ordering::Relaxed), 2);
+        drop(b);
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+
+        // Moving the data out from under a Weak also takes a new allocation,
+        // but the old one is only freed once the Weak is gone.
+        let w = Arc::downgrade(&a);
+        Arc::make_mut(&mut a).push('!');
+        assert_eq!(live.load(Ordering::Relaxed), 2);
+        drop(w);
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+
+        // try_unwrap leaves the allocation alone while it fails, and frees
+        // it once the data has been taken out.
+        let b = a.clone();
+        let a = Arc::try_unwrap(a).err().unwrap();
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+        drop(b);
+        let w = Arc::downgrade(&a);
+        assert_eq!(Arc::try_unwrap(a).ok().unwrap(), "a!!");
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+        drop(w);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn hash_map_key() {
+        use std::collections::HashMap;
+
+        let mut map = HashMap::new();
+        map.insert(Arc::<str>::from("a"), 1);
+        map.insert(Arc::from("b"), 2);
+        // Keys compare by value, not by allocation, and can be looked up
+        // through the Borrow impl.
+        map.insert(Arc::from("a"), 3);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["a"], 3);
+        assert_eq!(map.get(&Arc::from("b")), Some(&2));
+    }
+
+    #[test]
+    fn ord() {
+        let mut arcs: Vec<Arc<i32>> = [3, 1, 2].into_iter().map(Arc::new).collect();
+        arcs.sort();
+        assert_eq!(arcs.iter().map(|a| **a).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(Arc::new(1) < Arc::new(2));
+        assert_eq!(Arc::new(2).cmp(&Arc::new(2)), cmp::Ordering::Equal);
+        assert_eq!(Arc::new(f64::NAN).partial_cmp(&Arc::new(1.0)), None);
+    }
+
+    #[test]
+    fn pointer_format() {
+        let a = Arc::new(5);
+        let b = a.clone();
+        // The address of the data, the same for every clone.
+        assert_eq!(format!("{a:p}"), format!("{:p}", Arc::as_ptr(&a)));
+        assert_eq!(format!("{a:p}"), format!("{b:p}"));
+        assert_ne!(format!("{a:p}"), format!("{:p}", Arc::new(5)));
+    }
+
+    #[test]
+    fn unwind_safe() {
+        let a = Arc::new(1);
+        let w = Arc::downgrade(&a);
+        // As with std's Arc, both can be used from a catch_unwind closure.
+        let result = std::panic::catch_unwind(|| *a + *w.upgrade().unwrap());
+        assert_eq!(result.ok(), Some(2));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let a = Arc::new(vec![1, 2, 3]);
+        let pair = (a.clone(), a);
+        let json = serde_json::to_string(&pair).unwrap();
+        // Serialized as the data itself, with nothing of the Arc left.
+        assert_eq!(json, "[[1,2,3],[1,2,3]]");
+
+        let back: (Arc<Vec<i32>>, Arc<Vec<i32>>) = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, pair);
+        // The sharing is lost though, each gets an allocation of its own.
+        assert!(!Arc::ptr_eq(&back.0, &back.1));
+        assert_eq!(Arc::strong_count(&back.0), 1);
+    }
+
+    #[test]
+    fn from_vec() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(usize);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let a: Arc<[DetectDrop]> = Arc::from((0..5).map(DetectDrop).collect::<Vec<_>>());
+        // The items were moved, not dropped along with the Vec.
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert_eq!(a.iter().map(|d| d.0).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        let b = a.clone();
+        drop(a);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(b);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 5);
+
+        let empty: Arc<[DetectDrop]> = Arc::from(Vec::new());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn from_str() {
+        let a: Arc<str> = Arc::from("hello");
+        let b: Arc<str> = Arc::from(String::from("hello"));
+        assert_eq!(&*a, "hello");
+        assert_eq!(a, b);
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!(&*Arc::<str>::from(""), "");
+    }
+
+    #[test]
+    fn from_iter() {
+        let a: Arc<[i32]> = (1..=3).collect();
+        assert_eq!(*a, [1, 2, 3]);
+        let a: Arc<[String]> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(*a, ["a", "b"]);
+    }
+
+    #[cfg(feature = "debug-refs")]
+    #[test]
+    fn conversions_record_caller() {
+        use crate::debug_refs::held_from;
+
+        let _lock = ALIGNED_LOCK.lock().unwrap();
+        let (a, a_line) = (Arc::from(5), line!());
+        let (b, b_line) = (Arc::<[i32]>::from(vec![1, 2]), line!());
+        let (c, c_line) = (Arc::<str>::from("c"), line!());
+        let boxed: Box<dyn Value> = Box::new(Aligned(0));
+        let (d, d_line) = (Arc::<dyn Value>::from(boxed), line!());
+        // `collect` itself isn't tracked, so call `from_iter` directly.
+        let (e, e_line) = (Arc::<[i32]>::from_iter(0..2), line!());
+        let (f, f_line) = (Arc::<i32>::default(), line!());
+        let lines = [a_line, b_line, c_line, d_line, e_line, f_line];
+        for line in lines {
+            assert!(held_from(file!(), line));
+        }
+        drop((a, b, c, d, e, f));
+        for line in lines {
+            assert!(!held_from(file!(), line));
+        }
+    }
+
+    static ALIGNED_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    trait Value {
+        fn value(&self) -> u8;
+    }
+
+    /// Aligned beyond the counters, so the data doesn't directly follow
+    /// them.
+    #[repr(align(64))]
+    struct Aligned(u8);
+
+    impl Value for Aligned {
+        fn value(&self) -> u8 {
+            self.0
+        }
+    }
+
+    impl Drop for Aligned {
+        fn drop(&mut self) {
+            ALIGNED_DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Both tests below count drops of Aligned, so they share a lock.
+    static ALIGNED_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn from_box_dyn() {
+        let _lock = ALIGNED_LOCK.lock().unwrap();
+        let before = ALIGNED_DROPS.load(Ordering::Relaxed);
+
+        let boxed: Box<dyn Value> = Box::new(Aligned(7));
+        let a: Arc<dyn Value> = Arc::from(boxed);
+        assert_eq!(ALIGNED_DROPS.load(Ordering::Relaxed), before);
+        assert_eq!(Arc::as_ptr(&a) as *const u8 as usize % 64, 0);
+        assert_eq!(a.value(), 7);
+
+        let b = a.clone();
+        drop(a);
+        assert_eq!(b.value(), 7);
+        drop(b);
+        assert_eq!(ALIGNED_DROPS.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    fn raw_dyn() {
+        let _lock = ALIGNED_LOCK.lock().unwrap();
+        let before = ALIGNED_DROPS.load(Ordering::Relaxed);
+
+        let a: Arc<dyn Value> = Arc::unsize(Arc::new(Aligned(9)), |x| x as &dyn Value);
+        let ptr = Arc::into_raw(a);
+        // The vtable survives the trip through a raw pointer.
+        assert_eq!(unsafe { (*ptr).value() }, 9);
+        unsafe { Arc::increment_strong_count(ptr) };
+        let a = unsafe { Arc::from_raw(ptr) };
+        assert_eq!(Arc::strong_count(&a), 2);
+        assert_eq!(a.value(), 9);
+
+        unsafe { Arc::decrement_strong_count(ptr) };
+        assert_eq!(Arc::strong_count(&a), 1);
+        assert_eq!(ALIGNED_DROPS.load(Ordering::Relaxed), before);
+        drop(a);
+        assert_eq!(ALIGNED_DROPS.load(Ordering::Relaxed), before + 1);
+    }
+}
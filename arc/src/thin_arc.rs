@@ -0,0 +1,232 @@
+use std::alloc::Layout;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+
+use crate::simple_arc::Arc;
+
+/// A header followed by a slice, in a single allocation.
+///
+/// The length of the slice is stored alongside the header, which is what
+/// allows a [`ThinArc`] to get away with a thin pointer.
+#[repr(C)]
+pub struct HeaderSlice<H, T: ?Sized> {
+    pub header: H,
+    length: usize,
+    pub slice: T,
+}
+
+/// An [`Arc`] to a [`HeaderSlice`] behind a single-word pointer.
+///
+/// An `Arc<[T]>` is a fat pointer, carrying the length of the slice next to
+/// the address. For tables of many small shared slices (interned strings,
+/// tree nodes, ...) this doubles the size of every handle. Here the length is
+/// stored in the allocation instead, and the fat pointer is rebuilt from it
+/// whenever it's needed.
+///
+/// Apart from that this is an `Arc<HeaderSlice<H, [T]>>`, with all of the
+/// counting and dropping left to [`Arc`].
+pub struct ThinArc<H, T> {
+    /// The pointer from [`Arc::into_raw`], without its metadata. The `[T; 0]`
+    /// gives it the same alignment, and offsets for the header and length,
+    /// as the real thing.
+    ptr: NonNull<HeaderSlice<H, [T; 0]>>,
+    _marker: PhantomData<Arc<HeaderSlice<H, [T]>>>,
+}
+
+unsafe impl<H: Send + Sync, T: Send + Sync> Send for ThinArc<H, T> {}
+unsafe impl<H: Send + Sync, T: Send + Sync> Sync for ThinArc<H, T> {}
+
+impl<H, T> ThinArc<H, T> {
+    /// Create a [`ThinArc`] holding `header` and the items of `items`.
+    ///
+    /// Panics:
+    /// If `items` yields a different number of items than its `len()`
+    ///
+    /// Nothing is dropped in that case, nor if `items` itself panics: the
+    /// header, any items already written and the allocation are all leaked.
+    pub fn from_header_and_iter<I>(header: H, items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let mut items = items.into_iter();
+        let length = items.len();
+        let layout = Layout::new::<H>()
+            .extend(Layout::new::<usize>())
+            .unwrap()
+            .0
+            .extend(Layout::array::<T>(length).unwrap())
+            .unwrap()
+            .0
+            .pad_to_align();
+        let arc = unsafe {
+            Arc::new_in_place(
+                layout,
+                // The slice metadata (the length) becomes the metadata of the
+                // HeaderSlice, as the slice is its unsized tail.
+                |mem| {
+                    ptr::slice_from_raw_parts_mut(mem as *mut T, length) as *mut HeaderSlice<H, [T]>
+                },
+                |data| {
+                    ptr::addr_of_mut!((*data).header).write(header);
+                    ptr::addr_of_mut!((*data).length).write(length);
+                    let slice = ptr::addr_of_mut!((*data).slice) as *mut T;
+                    for i in 0..length {
+                        let item = items.next().expect("iterator shorter than its len()");
+                        slice.add(i).write(item);
+                    }
+                    assert!(items.next().is_none(), "iterator longer than its len()");
+                },
+            )
+        };
+        Self::from_arc(arc)
+    }
+
+    /// Turn an `Arc<HeaderSlice>` into a [`ThinArc`], without touching the
+    /// reference count.
+    pub fn from_arc(arc: Arc<HeaderSlice<H, [T]>>) -> Self {
+        let ptr = Arc::into_raw(arc) as *mut HeaderSlice<H, [T; 0]>;
+        Self {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turn this back into an `Arc<HeaderSlice>`, without touching the
+    /// reference count.
+    pub fn into_arc(thin: Self) -> Arc<HeaderSlice<H, [T]>> {
+        let thin = ManuallyDrop::new(thin);
+        unsafe { Arc::from_raw(thin.fat_ptr()) }
+    }
+
+    /// Number of [`ThinArc`]s (or [`Arc`]s) pointing to this allocation.
+    pub fn strong_count(thin: &Self) -> usize {
+        // Borrow our reference as an Arc for a moment. It's handed back with
+        // `into_raw` rather than forgotten, so that debug-refs sees it go back
+        // to being held through a raw pointer.
+        let arc = unsafe { Arc::from_raw(thin.fat_ptr()) };
+        let count = Arc::strong_count(&arc);
+        Arc::into_raw(arc);
+        count
+    }
+
+    /// Whether both [`ThinArc`]s point to the same allocation.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// Rebuild the fat pointer from the length stored in the allocation.
+    fn fat_ptr(&self) -> *const HeaderSlice<H, [T]> {
+        let thin = self.ptr.as_ptr();
+        // Safety: The header and length have the same offsets no matter the
+        // length of the slice.
+        let length = unsafe { (*thin).length };
+        ptr::slice_from_raw_parts(thin as *const T, length) as *const HeaderSlice<H, [T]>
+    }
+}
+
+impl<H, T> Deref for ThinArc<H, T> {
+    type Target = HeaderSlice<H, [T]>;
+    fn deref(&self) -> &Self::Target {
+        // Safety: We hold a strong reference, so the data is alive.
+        unsafe { &*self.fat_ptr() }
+    }
+}
+
+impl<H, T> Clone for ThinArc<H, T> {
+    #[cfg_attr(feature = "debug-refs", track_caller)]
+    fn clone(&self) -> Self {
+        unsafe { Arc::increment_strong_count(self.fat_ptr()) };
+        Self {
+            ptr: self.ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<H, T> Drop for ThinArc<H, T> {
+    fn drop(&mut self) {
+        unsafe { Arc::decrement_strong_count(self.fat_ptr()) }
+    }
+}
+
+impl<H, T> From<Arc<HeaderSlice<H, [T]>>> for ThinArc<H, T> {
+    fn from(arc: Arc<HeaderSlice<H, [T]>>) -> Self {
+        Self::from_arc(arc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn thin_pointer() {
+        assert_eq!(size_of::<ThinArc<u64, u8>>(), size_of::<usize>());
+        assert_eq!(size_of::<ThinArc<(), String>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<ThinArc<u8, u64>>>(), size_of::<usize>());
+    }
+
+    #[test]
+    fn clone_and_drop() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(usize);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let a = ThinArc::from_header_and_iter(DetectDrop(7), (0..3).map(DetectDrop));
+        let b = a.clone();
+        assert!(ThinArc::ptr_eq(&a, &b));
+        assert_eq!(ThinArc::strong_count(&a), 2);
+        assert_eq!(b.header.0, 7);
+        assert_eq!(b.slice.iter().map(|d| d.0).collect::<Vec<_>>(), [0, 1, 2]);
+
+        drop(a);
+        assert_eq!(ThinArc::strong_count(&b), 1);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+
+        // The header and all three items go with the last handle.
+        drop(b);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 4);
+    }
+
+    #[cfg(feature = "debug-refs")]
+    #[test]
+    fn debug_refs_balanced() {
+        use crate::debug_refs::{live_allocations, HeldBy};
+
+        let a = ThinArc::from_header_and_iter(1u32, [2u64, 3]);
+        let b = a.clone();
+        assert_eq!(ThinArc::strong_count(&a), 2);
+
+        // The registry is keyed by the start of the allocation, which is the
+        // counters in front of the HeaderSlice.
+        let offset = Layout::new::<[usize; 2]>()
+            .extend(Layout::for_value(&*a))
+            .unwrap()
+            .1;
+        let address = &*a as *const HeaderSlice<u32, [u64]> as *const u8 as usize - offset;
+        let holders = |address| {
+            live_allocations()
+                .into_iter()
+                .find(|allocation| allocation.address == address)
+                .map(|allocation| allocation.holders)
+        };
+        // Both references are held as raw pointers, with nothing left
+        // behind by `strong_count`.
+        assert_eq!(holders(address), Some(vec![(HeldBy::Raw, 2)]));
+
+        drop(a);
+        drop(b);
+        assert_eq!(holders(address), None);
+    }
+}
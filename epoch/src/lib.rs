@@ -0,0 +1,482 @@
+//! Epoch-based memory reclamation.
+//!
+//! Lock-free structures can't free a node as soon as it's unlinked, as another
+//! thread may have loaded a pointer to it just before and still be reading it.
+//! Here every thread [`pin`]s itself before touching shared nodes, which
+//! records the global epoch it has seen. Unlinked nodes are handed to
+//! [`Guard::defer_destroy`], which holds on to them until every thread has
+//! moved on to a later epoch, at which point nothing can still be using them.
+//!
+//! The global epoch only advances once every pinned thread has seen the
+//! current one. So garbage from epoch `e` is safe to free once the global
+//! epoch has reached `e + 2`: a thread pinned at `e` could still have a
+//! pointer to it, but a thread pinned at `e + 1` only pinned after the
+//! garbage was unlinked.
+//!
+//! Compared to `arc::hazard`, a reader protects everything it touches with a
+//! single store when pinning, rather than a store per pointer. The price is
+//! that one thread staying pinned holds up the freeing of all garbage.
+//!
+//! A Treiber stack, shared between threads:
+//!
+//! ```
+//! use std::mem::ManuallyDrop;
+//! use std::ptr;
+//! use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+//!
+//! struct Node<T> {
+//!     // The data is moved out by `pop`, the node itself is destroyed later.
+//!     data: ManuallyDrop<T>,
+//!     next: *mut Node<T>,
+//! }
+//!
+//! // Count the nodes that have been destroyed, to check that the deferred
+//! // destruction really happens.
+//! static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+//!
+//! impl<T> Drop for Node<T> {
+//!     fn drop(&mut self) {
+//!         DESTROYED.fetch_add(1, Ordering::Relaxed);
+//!     }
+//! }
+//!
+//! struct Stack<T> {
+//!     head: AtomicPtr<Node<T>>,
+//! }
+//!
+//! impl<T: Send> Stack<T> {
+//!     fn push(&self, data: T) {
+//!         let node = Box::into_raw(Box::new(Node {
+//!             data: ManuallyDrop::new(data),
+//!             next: ptr::null_mut(),
+//!         }));
+//!         let mut head = self.head.load(Ordering::Relaxed);
+//!         loop {
+//!             unsafe { (*node).next = head };
+//!             match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+//!                 Ok(_) => return,
+//!                 Err(h) => head = h,
+//!             }
+//!         }
+//!     }
+//!
+//!     fn pop(&self) -> Option<T> {
+//!         let guard = epoch::pin();
+//!         loop {
+//!             let head = self.head.load(Ordering::Acquire);
+//!             if head.is_null() {
+//!                 return None;
+//!             }
+//!             // Safe to dereference, head can't be freed while we're pinned.
+//!             let next = unsafe { (*head).next };
+//!             if self
+//!                 .head
+//!                 .compare_exchange(head, next, Ordering::Relaxed, Ordering::Relaxed)
+//!                 .is_ok()
+//!             {
+//!                 let data = unsafe { ptr::read(&*(*head).data) };
+//!                 unsafe { guard.defer_destroy(head) };
+//!                 return Some(data);
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let stack = Stack { head: AtomicPtr::new(ptr::null_mut()) };
+//! let n = if cfg!(miri) { 100 } else { 10_000 };
+//! std::thread::scope(|s| {
+//!     for t in 0..4 {
+//!         let stack = &stack;
+//!         s.spawn(move || {
+//!             for i in 0..n {
+//!                 stack.push(Box::new(t * n + i));
+//!                 assert!(stack.pop().is_some());
+//!             }
+//!             // Hand over the garbage this thread is still holding.
+//!             epoch::pin().flush();
+//!         });
+//!     }
+//! });
+//! assert!(stack.pop().is_none());
+//!
+//! // With no other threads around, every flush moves the epoch on by one.
+//! // Two epochs on, all of the popped nodes have been destroyed.
+//! for _ in 0..2 {
+//!     epoch::pin().flush();
+//! }
+//! assert_eq!(DESTROYED.load(Ordering::Relaxed), 4 * n);
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+// Both thresholds are lowered under Miri, whose runs are too short to ever
+// reach the real ones. Otherwise nothing would be freed until the end.
+
+/// Number of deferred destructions a thread collects before handing them over
+/// to the global garbage list.
+const BAG_CAPACITY: usize = if cfg!(miri) { 4 } else { 64 };
+
+/// Every this many pins, a thread tries to advance the epoch and free garbage.
+const PINS_BETWEEN_COLLECT: usize = if cfg!(miri) { 8 } else { 128 };
+
+/// Set in the epoch of a [`Local`] while its thread is pinned. The epoch itself
+/// is stored shifted left by one.
+const PINNED: usize = 1;
+
+/// A destructor to be run later.
+struct Deferred {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+impl Deferred {
+    unsafe fn call(self) {
+        (self.drop)(self.ptr)
+    }
+}
+
+/// A bag of garbage that has been handed over to the global list, along with
+/// the epoch it was handed over in.
+struct SealedBag {
+    epoch: usize,
+    deferred: Vec<Deferred>,
+    next: *mut SealedBag,
+}
+
+/// The part of a thread's state that other threads look at.
+struct Local {
+    /// The global epoch this thread was pinned at (shifted left by one) with
+    /// [`PINNED`] set, or 0 when not pinned.
+    epoch: AtomicUsize,
+    /// Whether a thread currently owns this entry. Entries are never freed,
+    /// but are reused by later threads.
+    active: AtomicBool,
+    /// Next entry in the list, which never changes once the entry is added.
+    next: *mut Local,
+}
+
+struct Global {
+    epoch: AtomicUsize,
+    /// List of all threads that have ever been pinned, which only grows.
+    locals: AtomicPtr<Local>,
+    /// Stack of garbage handed over by threads, waiting to be freed.
+    garbage: AtomicPtr<SealedBag>,
+}
+
+// The raw pointers inside are only touched through atomics, and
+// `defer_destroy` requires the garbage to be fine to send across.
+unsafe impl Sync for Global {}
+
+static GLOBAL: Global = Global {
+    epoch: AtomicUsize::new(0),
+    locals: AtomicPtr::new(ptr::null_mut()),
+    garbage: AtomicPtr::new(ptr::null_mut()),
+};
+
+impl Global {
+    /// Find an unused [`Local`], or add a new one.
+    fn register(&self) -> &'static Local {
+        for local in self.locals() {
+            if !local.active.load(Ordering::Relaxed)
+                && local
+                    .active
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return local;
+            }
+        }
+        let new = Box::into_raw(Box::new(Local {
+            epoch: AtomicUsize::new(0),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.locals.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*new).next = head };
+            // Release so that a thread following the list sees `next`.
+            match self
+                .locals
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return unsafe { &*new },
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    fn locals(&self) -> impl Iterator<Item = &'static Local> {
+        let mut local = self.locals.load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            let l = unsafe { local.as_ref()? };
+            local = l.next;
+            Some(l)
+        })
+    }
+
+    /// Hand over a bag of garbage, to be freed two epochs from now.
+    fn push_bag(&self, deferred: Vec<Deferred>) {
+        // The garbage has been unlinked before this point. Make sure that the
+        // epoch we load is no earlier than the one any thread that might
+        // still see it has been pinned at.
+        fence(Ordering::SeqCst);
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        self.push_sealed(Box::into_raw(Box::new(SealedBag {
+            epoch,
+            deferred,
+            next: ptr::null_mut(),
+        })));
+    }
+
+    fn push_sealed(&self, bag: *mut SealedBag) {
+        let mut head = self.garbage.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*bag).next = head };
+            // Release so that whoever frees the garbage sees the bag.
+            match self.garbage.compare_exchange_weak(
+                head,
+                bag,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Move the global epoch on by one, if every pinned thread has seen the
+    /// current one.
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        // Pairs with the fence in `Handle::pin`: either we see that a thread
+        // has pinned itself, or it sees the epoch we're about to move on from.
+        fence(Ordering::SeqCst);
+        for local in self.locals() {
+            let local_epoch = local.epoch.load(Ordering::Relaxed);
+            if local_epoch & PINNED != 0 && local_epoch != (epoch << 1) | PINNED {
+                // Still pinned in an older epoch.
+                return epoch;
+            }
+        }
+        // Acquire to have everything those threads did while pinned happen
+        // before the epoch moves on, and Release to pass that on to `collect`.
+        fence(Ordering::Acquire);
+        match self.epoch.compare_exchange(
+            epoch,
+            epoch.wrapping_add(1),
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => epoch.wrapping_add(1),
+            Err(e) => e,
+        }
+    }
+
+    /// Free all garbage that is at least two epochs old.
+    fn collect(&self) {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        // Take the whole list, so concurrent collectors don't see the same
+        // bags. Acquire matches the Release in `push_sealed`.
+        let mut bag = self.garbage.swap(ptr::null_mut(), Ordering::Acquire);
+        while !bag.is_null() {
+            let next = unsafe { (*bag).next };
+            if epoch.wrapping_sub(unsafe { (*bag).epoch }) >= 2 {
+                let bag = unsafe { Box::from_raw(bag) };
+                for deferred in bag.deferred {
+                    unsafe { deferred.call() };
+                }
+            } else {
+                self.push_sealed(bag);
+            }
+            bag = next;
+        }
+    }
+}
+
+/// The state of a thread which participates in epoch-based reclamation.
+struct Handle {
+    local: &'static Local,
+    /// Number of [`Guard`]s alive on this thread. Only the first one pins.
+    guards: Cell<usize>,
+    pins: Cell<usize>,
+    /// Garbage collected by this thread, not yet handed over to [`GLOBAL`].
+    bag: RefCell<Vec<Deferred>>,
+}
+
+thread_local! {
+    static HANDLE: Handle = Handle {
+        local: GLOBAL.register(),
+        guards: Cell::new(0),
+        pins: Cell::new(0),
+        bag: RefCell::new(Vec::new()),
+    };
+}
+
+impl Handle {
+    fn pin(&self) {
+        let guards = self.guards.get();
+        self.guards.set(guards + 1);
+        if guards != 0 {
+            return;
+        }
+        let epoch = GLOBAL.epoch.load(Ordering::Relaxed);
+        self.local
+            .epoch
+            .store((epoch << 1) | PINNED, Ordering::Relaxed);
+        // Our pin must be visible before we go on to load any pointers,
+        // otherwise the epoch could advance twice and free what they point to.
+        // Only a SeqCst fence orders a store before a later load.
+        fence(Ordering::SeqCst);
+
+        let pins = self.pins.get().wrapping_add(1);
+        self.pins.set(pins);
+        if pins.is_multiple_of(PINS_BETWEEN_COLLECT) {
+            GLOBAL.try_advance();
+            GLOBAL.collect();
+        }
+    }
+
+    fn unpin(&self) {
+        let guards = self.guards.get() - 1;
+        self.guards.set(guards);
+        if guards == 0 {
+            // Release so that everything we did while pinned happens before
+            // the epoch can move on.
+            self.local.epoch.store(0, Ordering::Release);
+        }
+    }
+
+    fn defer(&self, deferred: Deferred) {
+        let mut bag = self.bag.borrow_mut();
+        bag.push(deferred);
+        if bag.len() >= BAG_CAPACITY {
+            GLOBAL.push_bag(std::mem::take(&mut *bag));
+        }
+    }
+
+    fn flush(&self) {
+        let bag = std::mem::take(&mut *self.bag.borrow_mut());
+        if !bag.is_empty() {
+            GLOBAL.push_bag(bag);
+        }
+        GLOBAL.try_advance();
+        GLOBAL.collect();
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // Hand over whatever is left, so another thread frees it.
+        let bag = std::mem::take(self.bag.get_mut());
+        if !bag.is_empty() {
+            GLOBAL.push_bag(bag);
+        }
+        self.local.epoch.store(0, Ordering::Release);
+        self.local.active.store(false, Ordering::Release);
+    }
+}
+
+/// Proof that the current thread is pinned, see [`pin`].
+///
+/// Pointers loaded from a shared structure while a guard is alive stay valid
+/// until it is dropped, even if they're unlinked and deferred in the
+/// meantime.
+pub struct Guard {
+    // Pinning is per thread, so the guard has to stay on this thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Pin the current thread, until the returned [`Guard`] is dropped.
+///
+/// Pinning again while already pinned is cheap, only the outermost guard
+/// does the work.
+pub fn pin() -> Guard {
+    HANDLE.with(Handle::pin);
+    Guard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Guard {
+    /// Drop the [`Box`] behind `ptr` once no thread can still be using it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from [`Box::into_raw`], must already be unlinked so
+    /// that threads pinning from now on can't reach it, and may not be
+    /// deferred twice. It may be dropped on any thread, so it must be fine to
+    /// send it across (such as a node holding a `Send` value).
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        unsafe fn drop_box<T>(ptr: *mut ()) {
+            drop(Box::from_raw(ptr as *mut T));
+        }
+        HANDLE.with(|handle| {
+            handle.defer(Deferred {
+                ptr: ptr as *mut (),
+                drop: drop_box::<T>,
+            })
+        });
+    }
+
+    /// Hand over this thread's garbage, and free whatever can be freed.
+    ///
+    /// Garbage is otherwise only handed over once enough has built up, which
+    /// leaves a thread that rarely defers anything holding on to it.
+    pub fn flush(&self) {
+        HANDLE.with(Handle::flush);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        HANDLE.with(Handle::unpin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The epoch is global, so this is kept to a single test, which can't
+    // have another moving the epoch on behind its back.
+    #[test]
+    fn freed_two_epochs_later() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let guard = pin();
+        let epoch = GLOBAL.epoch.load(Ordering::Relaxed);
+        unsafe { guard.defer_destroy(Box::into_raw(Box::new(DetectDrop))) };
+
+        // The garbage is handed over in `epoch`. We're pinned in the current
+        // epoch, so it can move on once, but that's not enough to free it.
+        guard.flush();
+        assert_eq!(GLOBAL.epoch.load(Ordering::Relaxed), epoch + 1);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+
+        // Still pinned in `epoch`, which holds up the epoch from here on.
+        assert_eq!(GLOBAL.try_advance(), epoch + 1);
+        GLOBAL.collect();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(guard);
+
+        // A thread pinning in `epoch + 1` can't have seen the garbage, so it
+        // doesn't stop it being freed.
+        let guard = pin();
+        assert_eq!(GLOBAL.try_advance(), epoch + 2);
+        GLOBAL.collect();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+        drop(guard);
+    }
+}
@@ -0,0 +1,43 @@
+//! Compares the backoff strategies of `SpinLock`, with threads hammering a
+//! single lock around a short critical section.
+//!
+//! Run with `cargo bench --bench contention`.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::Instant;
+
+use spinlock::backoff::{Backoff, Exponential, Spin, SpinThenYield, TestAndTestAndSet};
+use spinlock::SpinLock;
+
+/// Lock acquisitions in total, split between the threads.
+const ITERATIONS: usize = 1_000_000;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn bench<B: Backoff>(name: &str, threads: usize) {
+    let lock = SpinLock::<u64, B>::with_backoff(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS / threads {
+                    *black_box(&lock).lock() += 1;
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<20} {threads:>2} threads {elapsed:>12.2?} ({:.2} ns/lock)",
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    for threads in THREADS {
+        bench::<Spin>("Spin", threads);
+        bench::<TestAndTestAndSet>("TestAndTestAndSet", threads);
+        bench::<Exponential>("Exponential", threads);
+        bench::<SpinThenYield>("SpinThenYield", threads);
+    }
+}
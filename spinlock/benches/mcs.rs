@@ -0,0 +1,54 @@
+//! Compares `McsLock` against `SpinLock` (and `TicketLock`, the other fair
+//! lock) at increasing numbers of threads hammering a single lock.
+//!
+//! Thread counts above the number of cores are skipped. A spinning thread
+//! that has been descheduled can't unlock or take a handed over lock, so
+//! those runs would mostly measure the OS scheduler.
+//!
+//! Run with `cargo bench --bench mcs`.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::Instant;
+
+use spinlock::mcs::McsLock;
+use spinlock::ticket::TicketLock;
+use spinlock::SpinLock;
+
+/// Lock acquisitions in total, split between the threads.
+const ITERATIONS: usize = 1_000_000;
+const THREADS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+fn time(name: &str, threads: usize, f: impl Fn() + Sync) {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS / threads {
+                    f();
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<12} {threads:>2} threads {elapsed:>12.2?} ({:.2} ns/lock)",
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    for threads in THREADS {
+        if threads > cores {
+            println!("skipping {threads} threads, only {cores} cores");
+            continue;
+        }
+        let spin = SpinLock::new(0u64);
+        time("SpinLock", threads, || *black_box(&spin).lock() += 1);
+        let ticket = TicketLock::new(0u64);
+        time("TicketLock", threads, || *black_box(&ticket).lock() += 1);
+        let mcs = McsLock::new(0u64);
+        time("McsLock", threads, || *black_box(&mcs).lock() += 1);
+    }
+}
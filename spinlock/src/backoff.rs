@@ -0,0 +1,110 @@
+//! Strategies for waiting between attempts to take a [`SpinLock`].
+//!
+//! Every attempt at the lock is a `swap`, which needs the cache line holding
+//! the flag in an exclusive state. With many threads spinning, that cache line
+//! bounces between their cores on every attempt, slowing down the thread that
+//! is trying to unlock as well. How a thread waits before trying again makes
+//! a big difference to this.
+//!
+//! [`SpinLock`]: crate::SpinLock
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How to wait after failing to take a lock, before trying again.
+///
+/// A fresh value (from [`Default`]) is created for every call to `lock`, so
+/// a strategy can keep track of how long it has been waiting.
+pub trait Backoff: Default {
+    /// Wait a while after finding `locked` set.
+    fn backoff(&mut self, locked: &AtomicBool);
+}
+
+/// Used by `SpinLock<T>` unless another strategy is picked.
+pub type DefaultBackoff = TestAndTestAndSet;
+
+/// Try again straight away, the original behaviour of the [`SpinLock`].
+///
+/// [`SpinLock`]: crate::SpinLock
+#[derive(Default)]
+pub struct Spin;
+
+impl Backoff for Spin {
+    fn backoff(&mut self, _locked: &AtomicBool) {
+        std::hint::spin_loop();
+    }
+}
+
+/// Spin on a plain load until the lock looks free, before trying the `swap`
+/// again.
+///
+/// A load only needs the cache line in a shared state, so all of the waiting
+/// threads can read their own copy of it without any traffic, until the
+/// unlock invalidates them.
+#[derive(Default)]
+pub struct TestAndTestAndSet;
+
+impl Backoff for TestAndTestAndSet {
+    fn backoff(&mut self, locked: &AtomicBool) {
+        // Relaxed is enough, it's the swap in `lock` that synchronises.
+        while locked.load(Ordering::Relaxed) {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Wait twice as long after every failed attempt, up to a limit.
+///
+/// Under heavy contention this spreads out the attempts of the waiting
+/// threads, so that fewer of them fight over the cache line at once.
+#[derive(Default)]
+pub struct Exponential {
+    step: u32,
+}
+
+impl Exponential {
+    /// Waiting is capped at `2^MAX_STEP` spins.
+    const MAX_STEP: u32 = 10;
+}
+
+impl Backoff for Exponential {
+    fn backoff(&mut self, _locked: &AtomicBool) {
+        for _ in 0..1 << self.step {
+            std::hint::spin_loop();
+        }
+        if self.step < Self::MAX_STEP {
+            self.step += 1;
+        }
+    }
+}
+
+/// Spin for a while, then start yielding to the OS scheduler.
+///
+/// When the lock is held for longer than expected, or when there are more
+/// threads than cores, the holder may not even be running. Spinning only
+/// takes time away from it then, while yielding gives it a chance to finish.
+#[derive(Default)]
+pub struct SpinThenYield {
+    spins: u32,
+}
+
+impl SpinThenYield {
+    /// Number of failed attempts before yielding.
+    const SPIN_LIMIT: u32 = 100;
+
+    /// Wait a while, without a flag to look at. This is for the fair locks,
+    /// whose waiters watch something other than an [`AtomicBool`].
+    pub fn snooze(&mut self) {
+        if self.spins < Self::SPIN_LIMIT {
+            self.spins += 1;
+            std::hint::spin_loop();
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl Backoff for SpinThenYield {
+    fn backoff(&mut self, _locked: &AtomicBool) {
+        self.snooze();
+    }
+}
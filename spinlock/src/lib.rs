@@ -1,16 +1,40 @@
 use std::{
     cell::UnsafeCell,
+    marker::PhantomData,
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicBool, Ordering},
 };
 
-pub struct Guard<'a, T> {
-    lock: &'a SpinLock<T>,
+pub mod backoff;
+pub mod mcs;
+pub mod ticket;
+
+use backoff::{Backoff, DefaultBackoff};
+
+/// Access to the data while holding a [`SpinLock`].
+///
+/// Sharing a `&Guard` with another thread shares a `&T`, so the guard is only
+/// `Sync` when `T` is. Otherwise two threads could use a `Cell` at once:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>(_: &T) {}
+/// let lock = spinlock::SpinLock::new(std::cell::Cell::new(1));
+/// assert_sync(&lock.lock());
+/// ```
+pub struct Guard<'a, T, B = DefaultBackoff> {
+    lock: &'a SpinLock<T, B>,
+    // A `&SpinLock` is Sync whenever `T: Send`, which is not enough for the
+    // guard. This opts out of the automatic impls, for the ones below.
+    _marker: PhantomData<*const ()>,
 }
 
+// Handing the guard to another thread hands over the `&mut T`.
+unsafe impl<T: Send, B> Send for Guard<'_, T, B> {}
+unsafe impl<T: Sync, B> Sync for Guard<'_, T, B> {}
+
 // Implementation of [`Deref`] and [`DerefMut`] enable the [`Guard`] pattern to
 // be used here, rather than exposing an `pub unsafe fn unlock(...)` interface.
-impl<T> Deref for Guard<'_, T> {
+impl<T, B> Deref for Guard<'_, T, B> {
     type Target = T;
     fn deref(&self) -> &T {
         // Existence of the guard implies we have an exclusive lock, so this is
@@ -19,7 +43,7 @@ impl<T> Deref for Guard<'_, T> {
     }
 }
 
-impl<T> DerefMut for Guard<'_, T> {
+impl<T, B> DerefMut for Guard<'_, T, B> {
     fn deref_mut(&mut self) -> &mut T {
         // Existence of the guard implies we have an exclusive lock, so this is
         // safe to do.
@@ -27,10 +51,12 @@ impl<T> DerefMut for Guard<'_, T> {
     }
 }
 
-impl<T> Drop for Guard<'_, T> {
+impl<T, B> Drop for Guard<'_, T, B> {
     fn drop(&mut self) {
-        // When the guard is dropped, we should unlock
-        self.lock.locked.store(false, Ordering::Relaxed)
+        // When the guard is dropped, we should unlock. Release matches the
+        // Acquire in `lock`, so that everything we did to the data while
+        // holding the lock is visible to whoever locks it next.
+        self.lock.locked.store(false, Ordering::Release)
     }
 }
 
@@ -46,30 +72,147 @@ impl<T> Drop for Guard<'_, T> {
 ///
 /// Extra details:
 /// https://stackoverflow.com/questions/5869825/when-should-one-use-a-spinlock-instead-of-mutex
-pub struct SpinLock<T> {
-    pub data: UnsafeCell<T>,
+///
+/// As `new` is a `const fn`, a [`SpinLock`] can live in a static. Here with
+/// several threads incrementing a counter, which only adds up if every
+/// increment is visible to the next thread to take the lock:
+///
+/// ```
+/// use spinlock::SpinLock;
+///
+/// static COUNTER: SpinLock<u64> = SpinLock::new(0);
+///
+/// std::thread::scope(|s| {
+///     for _ in 0..8 {
+///         s.spawn(|| {
+///             for _ in 0..10_000 {
+///                 *COUNTER.lock() += 1;
+///             }
+///         });
+///     }
+/// });
+/// assert_eq!(*COUNTER.lock(), 80_000);
+/// ```
+///
+/// The data can only be reached through a [`Guard`], or through the methods
+/// that spell out why no lock is needed. Safe code can't get at the cell
+/// directly:
+///
+/// ```compile_fail
+/// let lock = spinlock::SpinLock::new(1);
+/// unsafe { *lock.data.get() += 1 };
+/// ```
+///
+/// ```compile_fail
+/// let lock = spinlock::SpinLock::new(1);
+/// let spinlock::SpinLock { data, .. } = lock;
+/// ```
+///
+/// How a thread waits for the lock to come free is up to `B`, see the
+/// [`backoff`] module for the strategies on offer:
+///
+/// ```
+/// use spinlock::{backoff::Exponential, SpinLock};
+///
+/// let lock = SpinLock::<_, Exponential>::with_backoff(0);
+/// *lock.lock() += 1;
+/// ```
+pub struct SpinLock<T, B = DefaultBackoff> {
+    data: UnsafeCell<T>,
     locked: AtomicBool,
+    // The strategy is only a type, and has no bearing on Send and Sync.
+    _backoff: PhantomData<fn() -> B>,
 }
 
 // The use of [`UnsafeCell`] means we must promise to the compiler that this
 // is okay to do.
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+unsafe impl<T, B> Sync for SpinLock<T, B> where T: Send {}
 
 impl<T> SpinLock<T> {
-    pub fn new(inner: T) -> Self {
+    // Kept separate from `with_backoff`, as a default type parameter isn't
+    // used for inference. `SpinLock::new(0)` would otherwise need annotating.
+    pub const fn new(inner: T) -> Self {
+        Self::with_backoff(inner)
+    }
+}
+
+impl<T, B> SpinLock<T, B> {
+    /// Create a [`SpinLock`] which waits using the strategy `B`.
+    pub const fn with_backoff(inner: T) -> Self {
         Self {
             data: UnsafeCell::new(inner),
             locked: AtomicBool::new(false),
+            _backoff: PhantomData,
         }
     }
 
     /// Acquire an exclusive mutable lock as a [`Guard`].
     ///
     /// The returned [`Guard`] enables unlocking the [`SpinLock`] when dropped.
-    pub fn lock(&self) -> Guard<T> {
+    pub fn lock(&self) -> Guard<'_, T, B>
+    where
+        B: Backoff,
+    {
+        let mut backoff = B::default();
         while self.locked.swap(true, Ordering::Acquire) {
-            std::hint::spin_loop();
+            backoff.backoff(&self.locked);
+        }
+        Guard {
+            lock: self,
+            _marker: PhantomData,
         }
-        Guard { lock: self }
+    }
+
+    /// Acquire the lock only if it is free right now, without spinning.
+    pub fn try_lock(&self) -> Option<Guard<'_, T, B>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            Some(Guard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    /// Whether the lock is currently held.
+    ///
+    /// This is only a snapshot, by the time it is returned another thread may
+    /// have locked or unlocked it.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Mutable access to the data without locking.
+    ///
+    /// Having `&mut self` means no other thread can hold the lock, so this is
+    /// safe without any atomic operations.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consume the lock, returning the data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Raw pointer to the data, for handing to FFI.
+    ///
+    /// Dereferencing it is only sound while holding the lock (or having
+    /// otherwise made sure nobody else is using the data).
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    /// Unlock without a [`Guard`], for recovering from a guard that was
+    /// leaked with `std::mem::forget`, or a lock taken across FFI.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held, and nothing may use the data through the
+    /// holder's [`Guard`] or [`SpinLock::data_ptr`] afterwards.
+    pub unsafe fn force_unlock(&self) {
+        // Release for the same reason as in `Guard::drop`.
+        self.locked.store(false, Ordering::Release)
     }
 }
@@ -0,0 +1,241 @@
+//! An MCS lock (after Mellor-Crummey and Scott): a spinlock where every
+//! waiting thread spins on a flag of its own.
+//!
+//! With [`SpinLock`] all waiters spin on the same `locked` flag, so every
+//! unlock invalidates the cache line in every waiting core, all of which then
+//! race to fetch it again. Here the waiters form a queue, each one spinning on
+//! the `locked` flag in its own queue node. An unlock only touches the node
+//! of the next thread in line, so only that one core has to fetch anything.
+//!
+//! [`SpinLock`]: crate::SpinLock
+
+use std::{
+    cell::{RefCell, UnsafeCell},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use crate::backoff::SpinThenYield;
+
+/// The place in the queue of a thread holding or waiting for the lock.
+struct Node {
+    /// Set until the previous thread in the queue hands over the lock.
+    locked: AtomicBool,
+    /// The thread that queued up after us, once it has linked itself in.
+    next: AtomicPtr<Node>,
+}
+
+thread_local! {
+    /// Nodes that are not in any queue, kept around to save allocating one
+    /// for every lock. They are boxed as their addresses are handed out, and
+    /// must stay put while they're in a queue.
+    #[allow(clippy::vec_box)]
+    static FREE_NODES: RefCell<Vec<Box<Node>>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Node {
+    fn get() -> NonNull<Node> {
+        let node = FREE_NODES
+            .try_with(|nodes| nodes.borrow_mut().pop())
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| {
+                Box::new(Node {
+                    locked: AtomicBool::new(false),
+                    next: AtomicPtr::new(ptr::null_mut()),
+                })
+            });
+        NonNull::from(Box::leak(node))
+    }
+
+    /// Safety: `node` must come from `Node::get`, and no other thread may
+    /// still be using it.
+    unsafe fn put(node: NonNull<Node>) {
+        let node = Box::from_raw(node.as_ptr());
+        // Whilst the thread is exiting the cache may be gone, in which case
+        // the node is freed instead.
+        let _ = FREE_NODES.try_with(|nodes| nodes.borrow_mut().push(node));
+    }
+}
+
+pub struct Guard<'a, T> {
+    lock: &'a McsLock<T>,
+    /// Our node in the queue, which the next thread will link itself to.
+    node: NonNull<Node>,
+}
+
+unsafe impl<T: Sync> Sync for Guard<'_, T> {}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Existence of the guard implies we have an exclusive lock, so this is
+        // safe to do.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Existence of the guard implies we have an exclusive lock, so this is
+        // safe to do.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        let node = unsafe { self.node.as_ref() };
+        // Acquire matches the Release in `lock` where the next thread links
+        // itself in, so that its node is initialised before we write to it.
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Nobody seems to be waiting, so try to empty the queue. Release
+            // matches the Acquire of the next thread to lock, to pass on the
+            // changes we made while holding the lock.
+            if self
+                .lock
+                .tail
+                .compare_exchange(
+                    self.node.as_ptr(),
+                    ptr::null_mut(),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                unsafe { Node::put(self.node) };
+                return;
+            }
+            // Someone has queued up behind us, but not linked themselves in
+            // yet. They will in a moment, once they get to run.
+            let mut backoff = SpinThenYield::default();
+            loop {
+                next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                backoff.snooze();
+            }
+        }
+        // Hand over the lock. Release so that everything we did while holding
+        // the lock is visible to the next thread.
+        unsafe { (*next).locked.store(false, Ordering::Release) };
+        // The next thread is done with our node once it has linked itself in.
+        unsafe { Node::put(self.node) };
+    }
+}
+
+/// A fair spinlock, where each waiting thread spins on its own cache line.
+///
+/// Threads get the lock in the order they arrived, like with a
+/// [`TicketLock`], but without them all watching a shared counter.
+///
+/// Being fair makes it suffer even more when there are more threads than
+/// cores. The lock is handed to the next thread in the queue whether or not
+/// it is running, and an unlocking thread may have to wait for a thread that
+/// has only just joined the queue to link itself in. Either way the other
+/// threads can do nothing but wait for the scheduler. So both waits spin
+/// only briefly before yielding, as with [`SpinThenYield`].
+///
+/// ```
+/// use spinlock::mcs::McsLock;
+///
+/// static COUNTER: McsLock<u64> = McsLock::new(0);
+///
+/// std::thread::scope(|s| {
+///     for _ in 0..8 {
+///         s.spawn(|| {
+///             for _ in 0..10_000 {
+///                 *COUNTER.lock() += 1;
+///             }
+///         });
+///     }
+/// });
+/// assert_eq!(*COUNTER.lock(), 80_000);
+/// ```
+///
+/// [`TicketLock`]: crate::ticket::TicketLock
+pub struct McsLock<T> {
+    data: UnsafeCell<T>,
+    /// The node of the last thread in the queue, or null if it's unlocked.
+    tail: AtomicPtr<Node>,
+}
+
+// The use of [`UnsafeCell`] means we must promise to the compiler that this
+// is okay to do.
+unsafe impl<T> Sync for McsLock<T> where T: Send {}
+
+impl<T> McsLock<T> {
+    pub const fn new(inner: T) -> Self {
+        Self {
+            data: UnsafeCell::new(inner),
+            tail: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Join the queue and wait for our turn.
+    pub fn lock(&self) -> Guard<'_, T> {
+        let node = Node::get();
+        let n = unsafe { node.as_ref() };
+        n.locked.store(true, Ordering::Relaxed);
+        n.next.store(ptr::null_mut(), Ordering::Relaxed);
+        // Release so that the previous thread sees our node initialised, and
+        // Acquire for the changes made by the previous holder, should the
+        // queue have been empty.
+        let prev = self.tail.swap(node.as_ptr(), Ordering::AcqRel);
+        if !prev.is_null() {
+            // Release matches the Acquire in `Guard::drop`, see there.
+            unsafe { (*prev).next.store(node.as_ptr(), Ordering::Release) };
+            // Acquire matches the Release hand over in `Guard::drop`.
+            let mut backoff = SpinThenYield::default();
+            while n.locked.load(Ordering::Acquire) {
+                backoff.snooze();
+            }
+        }
+        Guard { lock: self, node }
+    }
+
+    /// Acquire the lock only if it is free right now, without queueing.
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        let node = Node::get();
+        let n = unsafe { node.as_ref() };
+        n.next.store(ptr::null_mut(), Ordering::Relaxed);
+        // Acquire for the changes made by the previous holder, Release so
+        // that the next thread sees our node initialised.
+        match self.tail.compare_exchange(
+            ptr::null_mut(),
+            node.as_ptr(),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Some(Guard { lock: self, node }),
+            Err(_) => {
+                unsafe { Node::put(node) };
+                None
+            }
+        }
+    }
+
+    /// Whether the lock is currently held.
+    ///
+    /// This is only a snapshot, by the time it is returned another thread may
+    /// have locked or unlocked it.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Mutable access to the data without locking.
+    ///
+    /// Having `&mut self` means no other thread can hold the lock, so this is
+    /// safe without any atomic operations.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consume the lock, returning the data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
@@ -0,0 +1,201 @@
+//! A ticket lock: a spinlock which hands out the lock in the order it was
+//! asked for.
+//!
+//! With the `AtomicBool` of [`SpinLock`], whichever thread happens to win the
+//! `swap` after an unlock gets the lock. That is often the thread which just
+//! unlocked it, as it still has the cache line, so others can go without for
+//! a long time. Here every thread takes a ticket, as at a deli counter, and
+//! waits for its number to come up.
+//!
+//! [`SpinLock`]: crate::SpinLock
+
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::backoff::SpinThenYield;
+
+/// Access to the data while holding a [`TicketLock`].
+///
+/// As with the guard of a [`SpinLock`], this is only `Sync` when `T` is:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>(_: &T) {}
+/// let lock = spinlock::ticket::TicketLock::new(std::cell::Cell::new(1));
+/// assert_sync(&lock.lock());
+/// ```
+///
+/// [`SpinLock`]: crate::SpinLock
+pub struct Guard<'a, T> {
+    lock: &'a TicketLock<T>,
+    // Opts out of the automatic impls, which would follow those of
+    // `&TicketLock` and so only need `T: Send`.
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send> Send for Guard<'_, T> {}
+unsafe impl<T: Sync> Sync for Guard<'_, T> {}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Existence of the guard implies we have an exclusive lock, so this is
+        // safe to do.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Existence of the guard implies we have an exclusive lock, so this is
+        // safe to do.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        // Only the holder of the lock changes `now_serving`, so there is no
+        // need for a read-modify-write. Release so that everything we did
+        // while holding the lock is visible to the next in line.
+        let serving = self.lock.now_serving.load(Ordering::Relaxed);
+        self.lock
+            .now_serving
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// A spinlock which is fair: threads get the lock in the order they called
+/// [`TicketLock::lock`].
+///
+/// A waiting thread is only ever overtaken by threads that were already
+/// waiting when it arrived, so nobody is starved.
+///
+/// The flip side is that the lock can only go to the thread holding the next
+/// ticket. If that thread isn't running, because there are more threads than
+/// cores, everyone behind it has to wait until the scheduler gets round to
+/// it, however often they spin. So waiters spin only briefly before yielding
+/// to the scheduler, as with [`SpinThenYield`], to give it that chance.
+///
+/// ```
+/// use spinlock::ticket::TicketLock;
+/// use std::sync::Mutex;
+///
+/// let lock = TicketLock::new(());
+/// let order = Mutex::new(Vec::new());
+/// std::thread::scope(|s| {
+///     let held = lock.lock();
+///     // Line up the threads one at a time, behind the lock we're holding.
+///     for i in 0..4 {
+///         let (lock, order) = (&lock, &order);
+///         s.spawn(move || {
+///             let _guard = lock.lock();
+///             order.lock().unwrap().push(i);
+///         });
+///         while lock.waiting() != i + 1 {
+///             std::thread::yield_now();
+///         }
+///     }
+///     drop(held);
+/// });
+/// // However the threads were scheduled, they were served in order.
+/// assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3]);
+/// ```
+pub struct TicketLock<T> {
+    data: UnsafeCell<T>,
+    /// The ticket for the next thread to arrive.
+    next_ticket: AtomicUsize,
+    /// The ticket of the thread holding the lock, or of the thread to get it
+    /// next if it is unlocked.
+    now_serving: AtomicUsize,
+}
+
+// The use of [`UnsafeCell`] means we must promise to the compiler that this
+// is okay to do.
+unsafe impl<T> Sync for TicketLock<T> where T: Send {}
+
+impl<T> TicketLock<T> {
+    pub const fn new(inner: T) -> Self {
+        Self {
+            data: UnsafeCell::new(inner),
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a ticket and wait for our turn.
+    pub fn lock(&self) -> Guard<'_, T> {
+        // Relaxed, as it's only the order of the tickets that matters. The
+        // counters wrap around, which is fine unless usize::MAX threads are
+        // waiting at once.
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        // Acquire matches the Release in `Guard::drop`.
+        let mut backoff = SpinThenYield::default();
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            backoff.snooze();
+        }
+        Guard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Acquire the lock only if it is free right now, without taking a
+    /// ticket and waiting.
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        // Acquire matches the Release in `Guard::drop` that moved
+        // `now_serving` on to this ticket.
+        let serving = self.now_serving.load(Ordering::Acquire);
+        // The lock is free exactly when the next ticket would be served
+        // straight away, in which case we take that ticket.
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| Guard {
+                lock: self,
+                _marker: PhantomData,
+            })
+    }
+
+    /// Whether the lock is currently held.
+    ///
+    /// This is only a snapshot, by the time it is returned another thread may
+    /// have locked or unlocked it.
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Number of threads waiting for the lock, not counting the one holding
+    /// it. Also only a snapshot.
+    pub fn waiting(&self) -> usize {
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        let next = self.next_ticket.load(Ordering::Relaxed);
+        // The two loads aren't synchronised, so `next` can be an older value
+        // which looks like it's behind `serving`. Nobody is waiting then.
+        match next.wrapping_sub(serving) {
+            n if n > usize::MAX / 2 => 0,
+            n => n.saturating_sub(1),
+        }
+    }
+
+    /// Mutable access to the data without locking.
+    ///
+    /// Having `&mut self` means no other thread can hold the lock, so this is
+    /// safe without any atomic operations.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consume the lock, returning the data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}